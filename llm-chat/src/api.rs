@@ -0,0 +1,326 @@
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+
+/// Errors returned by the provider's api, as opposed to local failures
+#[derive(Debug)]
+pub enum ApiError {
+    /// The api answered with an error status
+    Status { status: StatusCode, message: String },
+    /// Still rate limited after all retries
+    RateLimited {
+        retry_after: Option<Duration>,
+        message: String,
+    },
+    /// The request timed out
+    Timeout,
+    /// The server could not be reached
+    Network(reqwest::Error),
+    /// The response body was not what the provider should send
+    Malformed(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Status { status, message } => write!(f, "Api returned {status}: {message}"),
+            ApiError::RateLimited {
+                retry_after,
+                message,
+            } => {
+                write!(f, "Rate limited by the api: {message}")?;
+                if let Some(wait) = retry_after {
+                    write!(f, " (retry after {}s)", wait.as_secs())?;
+                }
+                Ok(())
+            }
+            ApiError::Timeout => write!(f, "Request to the api timed out"),
+            ApiError::Network(err) => write!(f, "Could not reach the api: {err}"),
+            ApiError::Malformed(msg) => write!(f, "Unexpected response from the api: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::Network(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            ApiError::Timeout
+        } else {
+            ApiError::Network(err)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every following one
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+}
+
+pub struct HttpClient {
+    pub client: Client,
+    pub retry: RetryPolicy,
+}
+
+impl HttpClient {
+    pub fn new(
+        timeout: Option<Duration>,
+        connect_timeout: Duration,
+        retry: RetryPolicy,
+    ) -> reqwest::Result<Self> {
+        let mut builder = Client::builder().connect_timeout(connect_timeout);
+        // A read timeout rather than a total one so long streamed answers are not cut off
+        if let Some(timeout) = timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        Ok(HttpClient {
+            client: builder.build()?,
+            retry,
+        })
+    }
+
+    /// Sends the request, retrying rate limits, server errors and network failures
+    /// with exponential backoff. `Retry-After` takes precedence over the backoff.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, ApiError> {
+        let mut attempt = 0;
+        loop {
+            let retry = request
+                .try_clone()
+                .filter(|_| attempt < self.retry.max_retries);
+            let current = match retry {
+                Some(clone) => clone,
+                None => return check_status(request.send().await?).await,
+            };
+
+            let (err, retry_after) = match current.send().await {
+                Ok(response) if !is_retryable(response.status()) => {
+                    return check_status(response).await
+                }
+                Ok(response) => {
+                    let retry_after = retry_after(response.headers());
+                    (check_status(response).await.unwrap_err(), retry_after)
+                }
+                Err(err) if err.is_builder() => return Err(err.into()),
+                Err(err) => (err.into(), None),
+            };
+
+            let delay = retry_after.unwrap_or_else(|| self.retry.backoff(attempt));
+            if delay > self.retry.max_delay {
+                return Err(err);
+            }
+            eprintln!("{err}, retrying in {:.1}s", delay.as_secs_f32());
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    // 529 is Anthropic's "overloaded"
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() || status.as_u16() == 529
+}
+
+/// Reads `Retry-After` given either as seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).ok();
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+/// Turns an error status into an [`ApiError`] carrying the message from the body
+async fn check_status(response: Response) -> Result<Response, ApiError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = retry_after(response.headers());
+    let body = response.text().await.unwrap_or_default();
+    let message = error_message(&body).unwrap_or_else(|| {
+        if body.trim().is_empty() {
+            status
+                .canonical_reason()
+                .unwrap_or("no details")
+                .to_string()
+        } else {
+            body.trim().to_string()
+        }
+    });
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        Err(ApiError::RateLimited {
+            retry_after,
+            message,
+        })
+    } else {
+        Err(ApiError::Status { status, message })
+    }
+}
+
+/// Error message from an OpenAI (`error.message`), Anthropic (`error.message`)
+/// or Ollama (`error`) error body
+fn error_message(body: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let error = value.get("error")?;
+    error
+        .get("message")
+        .unwrap_or(error)
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Reads a JSON response body
+pub async fn read_json(response: Response) -> Result<Value, ApiError> {
+    let body = response.text().await?;
+    serde_json::from_str(&body).map_err(|err| ApiError::Malformed(format!("{err} in {body:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> HttpClient {
+        let retry = RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        };
+        HttpClient::new(None, Duration::from_secs(5), retry).unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_rate_limit_then_succeeds() {
+        let mut server = mockito::Server::new_async().await;
+        let limited = server
+            .mock("POST", "/")
+            .with_status(429)
+            .with_header("retry-after", "0")
+            .expect(1)
+            .create_async()
+            .await;
+        let ok = server
+            .mock("POST", "/")
+            .with_body("{}")
+            .create_async()
+            .await;
+        let http = client();
+
+        let response = http
+            .send(http.client.post(server.url()).body("{}"))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        limited.assert_async().await;
+        ok.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn server_error_after_retries() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .with_status(500)
+            .with_body(r#"{"error":{"message":"boom","type":"server_error"}}"#)
+            .expect(3)
+            .create_async()
+            .await;
+        let http = client();
+
+        let err = http
+            .send(http.client.post(server.url()).body("{}"))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, ApiError::Status { status, message } if status.as_u16() == 500 && message == "boom")
+        );
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn client_error_is_not_retried() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .with_status(401)
+            .with_body(r#"{"error":"invalid api key"}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let http = client();
+
+        let err = http
+            .send(http.client.post(server.url()).body("{}"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Api returned 401 Unauthorized: invalid api key"
+        );
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn malformed_body() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/")
+            .with_body("<html>oops</html>")
+            .create_async()
+            .await;
+        let http = client();
+
+        let response = http
+            .send(http.client.post(server.url()).body("{}"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_json(response).await,
+            Err(ApiError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn retry_after_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "2".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), None);
+    }
+}
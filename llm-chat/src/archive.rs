@@ -0,0 +1,490 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local, NaiveDate};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use crate::history::{self, Entry, HistoryStore, JsonStore, Policy, Session};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Jsonl,
+    Html,
+}
+
+impl ExportFormat {
+    /// Format matching the extension of the output file
+    pub fn from_path(path: &Path) -> Option<ExportFormat> {
+        match path.extension()?.to_str()? {
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "jsonl" => Some(ExportFormat::Jsonl),
+            "html" | "htm" => Some(ExportFormat::Html),
+            _ => None,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// JSON history file of older versions
+    History,
+    /// Lines written by `export-history --format jsonl`
+    Jsonl,
+    /// conversations.json from a ChatGPT data export
+    Openai,
+}
+
+impl ImportFormat {
+    fn detect(path: &Path, content: &str) -> ImportFormat {
+        if path.extension().is_some_and(|ext| ext == "jsonl") {
+            return ImportFormat::Jsonl;
+        }
+        match serde_json::from_str::<Value>(content) {
+            Ok(Value::Array(_)) => ImportFormat::Openai,
+            Ok(Value::Object(object)) if !object.contains_key("prompt") => ImportFormat::History,
+            _ => ImportFormat::Jsonl,
+        }
+    }
+}
+
+/// Days to export, both ends included. Entries without a timestamp are left out
+/// when either end is set.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct DateRange {
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+}
+
+impl DateRange {
+    fn contains(&self, entry: &Entry) -> bool {
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        entry.local_day().is_some_and(|day| {
+            self.since.is_none_or(|since| day >= since)
+                && self.until.is_none_or(|until| day <= until)
+        })
+    }
+}
+
+/// One line of a JSONL export
+#[derive(Deserialize, Serialize)]
+struct Record {
+    session: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    #[serde(flatten)]
+    entry: Entry,
+}
+
+/// Renders the named sessions with their entries in the range. Sessions without
+/// entries in the range are left out, and nothing is rendered when none are left.
+pub fn export(
+    store: &dyn HistoryStore,
+    names: &[String],
+    range: DateRange,
+    format: ExportFormat,
+) -> Result<String> {
+    let mut sessions = vec![];
+    for name in names {
+        let Some(mut session) = store.session(name)? else {
+            bail!("No session named '{name}'");
+        };
+        session.chatlog.retain(|entry| range.contains(entry));
+        if !session.chatlog.is_empty() {
+            sessions.push((name.as_str(), session));
+        }
+    }
+    if sessions.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(match format {
+        ExportFormat::Markdown => to_markdown(&sessions),
+        ExportFormat::Jsonl => to_jsonl(&sessions)?,
+        ExportFormat::Html => to_html(&sessions),
+    })
+}
+
+fn local_time(entry: &Entry) -> Option<String> {
+    let time = DateTime::from_timestamp(entry.timestamp?, 0)?;
+    Some(
+        time.with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string(),
+    )
+}
+
+/// Time and model of the entry, as far as they are known
+fn entry_meta(entry: &Entry) -> String {
+    [local_time(entry), entry.model.clone()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" · ")
+}
+
+fn to_markdown(sessions: &[(&str, Session)]) -> String {
+    let mut out = String::new();
+    for (name, session) in sessions {
+        let _ = writeln!(out, "# {name}\n");
+        if let Some(system) = &session.system {
+            let _ = writeln!(out, "> {}\n", system.replace('\n', "\n> "));
+        }
+        for entry in &session.chatlog {
+            let _ = writeln!(out, "## {}\n", entry_meta(entry));
+            let _ = writeln!(out, "**Prompt**\n\n{}\n", entry.prompt.trim_end());
+            for call in entry.tool_log.iter().flat_map(|m| &m.tool_calls) {
+                let _ = writeln!(
+                    out,
+                    "_Tool_: `{} {}`\n",
+                    call.function.name, call.function.arguments
+                );
+            }
+            let _ = writeln!(out, "**Response**\n\n{}\n", entry.response.trim_end());
+        }
+    }
+    out
+}
+
+fn to_jsonl(sessions: &[(&str, Session)]) -> Result<String> {
+    let mut out = String::new();
+    for (name, session) in sessions {
+        for entry in &session.chatlog {
+            let record = Record {
+                session: name.to_string(),
+                system: session.system.clone(),
+                entry: entry.clone(),
+            };
+            out.push_str(&serde_json::to_string(&record)?);
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const HTML_STYLE: &str = "
+body { font-family: sans-serif; max-width: 50em; margin: 2em auto; padding: 0 1em; }
+.system, .meta, .tool { color: #666; }
+.prompt, .response { white-space: pre-wrap; padding: 0.5em 1em; border-radius: 6px; }
+.prompt { background: #eef3fb; }
+.response { background: #f5f5f5; }
+";
+
+fn to_html(sessions: &[(&str, Session)]) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Chat history</title>\n<style>{HTML_STYLE}</style>\n</head>\n<body>\n"
+    );
+    for (name, session) in sessions {
+        let _ = writeln!(out, "<h1>{}</h1>", escape_html(name));
+        if let Some(system) = &session.system {
+            let _ = writeln!(out, "<p class=\"system\">{}</p>", escape_html(system));
+        }
+        for entry in &session.chatlog {
+            let _ = writeln!(
+                out,
+                "<p class=\"meta\">{}</p>",
+                escape_html(&entry_meta(entry))
+            );
+            let _ = writeln!(
+                out,
+                "<div class=\"prompt\">{}</div>",
+                escape_html(entry.prompt.trim_end())
+            );
+            for call in entry.tool_log.iter().flat_map(|m| &m.tool_calls) {
+                let _ = writeln!(
+                    out,
+                    "<p class=\"tool\">Tool: <code>{} {}</code></p>",
+                    escape_html(&call.function.name),
+                    escape_html(&call.function.arguments)
+                );
+            }
+            let _ = writeln!(
+                out,
+                "<div class=\"response\">{}</div>",
+                escape_html(entry.response.trim_end())
+            );
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Adds the sessions in the file to the store, detecting the format from the
+/// file when it is not given. Entries are saved as the policy says, the same as
+/// new ones.
+pub fn import(
+    store: &mut dyn HistoryStore,
+    policy: &Policy,
+    path: &Path,
+    format: Option<ImportFormat>,
+) -> Result<()> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    match format.unwrap_or_else(|| ImportFormat::detect(path, &content)) {
+        ImportFormat::History => history::migrate(&JsonStore::open(path)?, store, policy),
+        ImportFormat::Jsonl => import_jsonl(store, policy, &content),
+        ImportFormat::Openai => import_openai(store, policy, &content),
+    }
+}
+
+fn import_jsonl(store: &mut dyn HistoryStore, policy: &Policy, content: &str) -> Result<()> {
+    let mut seen = HashSet::new();
+    for (n, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(line)
+            .with_context(|| format!("Invalid export record on line {}", n + 1))?;
+        // The system prompt is only taken for sessions the import creates
+        if seen.insert(record.session.clone()) && store.session(&record.session)?.is_none() {
+            store.set_settings(&record.session, None, record.system.as_deref())?;
+        }
+        policy.append(store, &record.session, &record.entry)?;
+    }
+    policy.prune(store)
+}
+
+#[derive(Deserialize)]
+struct Conversation {
+    #[serde(default)]
+    title: Option<String>,
+    mapping: HashMap<String, Node>,
+    #[serde(default)]
+    current_node: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    #[serde(default)]
+    message: Option<Message>,
+    #[serde(default)]
+    parent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    author: Author,
+    content: Content,
+    #[serde(default)]
+    create_time: Option<f64>,
+    /// Anything but `all` is a call to a tool such as the browser
+    #[serde(default)]
+    recipient: Option<String>,
+    #[serde(default)]
+    metadata: Metadata,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    role: String,
+}
+
+#[derive(Deserialize)]
+struct Content {
+    /// Text, or objects for images and other attachments
+    #[serde(default)]
+    parts: Vec<Value>,
+}
+
+#[derive(Deserialize, Default)]
+struct Metadata {
+    #[serde(default)]
+    model_slug: Option<String>,
+}
+
+impl Message {
+    fn text(&self) -> String {
+        let parts: Vec<&str> = self
+            .content
+            .parts
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        parts.join("\n").trim().to_string()
+    }
+}
+
+impl Conversation {
+    /// Messages on the branch that was shown last, oldest first
+    fn messages(&self) -> Vec<&Message> {
+        let mut messages = vec![];
+        let mut id = self.current_node.as_ref();
+        while let Some(node) = id.and_then(|id| self.mapping.get(id)) {
+            messages.extend(&node.message);
+            id = node.parent.as_ref();
+        }
+        messages.reverse();
+        messages
+    }
+
+    /// Pairs each user message with the assistant messages that follow it
+    fn entries(&self) -> Vec<Entry> {
+        let mut entries = vec![];
+        let mut current: Option<Entry> = None;
+        for message in self.messages() {
+            let text = message.text();
+            if text.is_empty() || message.recipient.as_deref().is_some_and(|r| r != "all") {
+                continue;
+            }
+            match (message.author.role.as_str(), &mut current) {
+                // Several prompts in a row without an answer become one
+                ("user", Some(entry)) if entry.response.is_empty() => {
+                    entry.prompt = format!("{}\n\n{text}", entry.prompt);
+                }
+                ("user", _) => {
+                    entries.extend(current.take());
+                    current = Some(Entry {
+                        prompt: text,
+                        response: String::new(),
+                        model: None,
+                        timestamp: message.create_time.map(|time| time as i64),
+                        prompt_tokens: None,
+                        response_tokens: None,
+                        tool_log: vec![],
+                        cached: false,
+                    });
+                }
+                ("assistant", Some(entry)) => {
+                    if !entry.response.is_empty() {
+                        entry.response.push_str("\n\n");
+                    }
+                    entry.response.push_str(&text);
+                    if entry.model.is_none() {
+                        entry.model = message.metadata.model_slug.clone();
+                    }
+                }
+                _ => {}
+            }
+        }
+        entries.extend(current.filter(|entry| !entry.response.is_empty()));
+        entries
+    }
+}
+
+fn import_openai(store: &mut dyn HistoryStore, policy: &Policy, content: &str) -> Result<()> {
+    let conversations: Vec<Conversation> =
+        serde_json::from_str(content).context("Invalid ChatGPT export")?;
+    for (n, conversation) in conversations.iter().enumerate() {
+        let entries = conversation.entries();
+        if entries.is_empty() {
+            continue;
+        }
+        let title = conversation
+            .title
+            .clone()
+            .filter(|title| !title.trim().is_empty())
+            .unwrap_or_else(|| format!("chatgpt-{}", n + 1));
+        // Conversations often share a title, later ones get a number
+        let mut name = title.clone();
+        let mut copy = 1;
+        while store.session(&name)?.is_some() {
+            copy += 1;
+            name = format!("{title} ({copy})");
+        }
+        for entry in &entries {
+            policy.append(store, &name, entry)?;
+        }
+    }
+    policy.prune(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::SqliteStore;
+    use crate::redact::Redactor;
+
+    #[test]
+    fn jsonl_round_trip() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        store.set_settings("a", None, Some("be brief")).unwrap();
+        let mut old = Entry::new("p1".to_string(), "r1".to_string(), "m");
+        old.timestamp = Some(0);
+        let new = Entry::new("<p2>".to_string(), "r2".to_string(), "m");
+        store.append("a", &old).unwrap();
+        store.append("a", &new).unwrap();
+
+        let names = ["a".to_string()];
+        let range = DateRange {
+            since: new.local_day(),
+            until: None,
+        };
+        let jsonl = export(&store, &names, range, ExportFormat::Jsonl).unwrap();
+        assert_eq!(jsonl.lines().count(), 1);
+        let html = export(&store, &names, range, ExportFormat::Html).unwrap();
+        assert!(html.contains("&lt;p2&gt;") && !html.contains("p1"));
+
+        let mut copy = SqliteStore::open_in_memory().unwrap();
+        import_jsonl(&mut copy, &Policy::default(), &jsonl).unwrap();
+        let session = copy.session("a").unwrap().unwrap();
+        assert_eq!(session.system.as_deref(), Some("be brief"));
+        assert_eq!(session.chatlog, [new]);
+    }
+
+    #[test]
+    fn chatgpt_export() {
+        let content = r#"[{
+            "title": "Rust",
+            "current_node": "4",
+            "mapping": {
+                "0": {"message": null, "parent": null},
+                "1": {"parent": "0", "message": {"author": {"role": "system"}, "content": {"parts": [""]}}},
+                "2": {"parent": "1", "message": {"author": {"role": "user"}, "create_time": 1700000000.5, "content": {"parts": ["What is a trait?"]}}},
+                "3": {"parent": "2", "message": {"author": {"role": "assistant"}, "metadata": {"model_slug": "gpt-4o"}, "content": {"parts": ["An interface."]}}},
+                "4": {"parent": "3", "message": {"author": {"role": "user"}, "content": {"parts": ["Thanks", {"asset": "image"}]}}},
+                "5": {"parent": "2", "message": {"author": {"role": "assistant"}, "content": {"parts": ["Old branch"]}}}
+            }
+        }]"#;
+        assert_eq!(
+            ImportFormat::detect(Path::new("conversations.json"), content),
+            ImportFormat::Openai
+        );
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        store
+            .append("Rust", &Entry::new(String::new(), String::new(), "m"))
+            .unwrap();
+        import_openai(&mut store, &Policy::default(), content).unwrap();
+
+        let chatlog = store.session("Rust (2)").unwrap().unwrap().chatlog;
+        assert_eq!(chatlog.len(), 1);
+        assert_eq!(chatlog[0].prompt, "What is a trait?");
+        assert_eq!(chatlog[0].response, "An interface.");
+        assert_eq!(chatlog[0].model.as_deref(), Some("gpt-4o"));
+        assert_eq!(chatlog[0].timestamp, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn imports_are_redacted() {
+        let policy = Policy {
+            redactor: Redactor::new(true, &[]).unwrap(),
+            ..Policy::default()
+        };
+        let path =
+            std::env::temp_dir().join(format!("llm_chat_import_{}.jsonl", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"session":"a","prompt":"my key is sk-abcdefghij0123456789xyz","response":"ok"}"#,
+        )
+        .unwrap();
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let imported = import(&mut store, &policy, &path, None);
+        fs::remove_file(&path).unwrap();
+        imported.unwrap();
+
+        let chatlog = store.session("a").unwrap().unwrap().chatlog;
+        assert_eq!(chatlog[0].prompt, "my key is [REDACTED]");
+    }
+}
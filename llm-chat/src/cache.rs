@@ -0,0 +1,188 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::provider::{ChatRequest, Reply};
+
+/// How long a cached reply is used when no other ttl is configured
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The `[cache]` table of the config file, replies are only cached when it is enabled
+/// here or with `--cache`:
+///
+/// ```toml
+/// [cache]
+/// enabled = true
+/// ttl = 86400
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seconds a reply is used for
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    /// Directory of the cache files, defaults to [`default_dir`]
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+}
+
+fn default_ttl() -> u64 {
+    DEFAULT_TTL.as_secs()
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            ttl: default_ttl(),
+            dir: None,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// The cache, `None` when it is disabled or there is nowhere to keep it
+    pub fn open(&self) -> Option<ResponseCache> {
+        if !self.enabled {
+            return None;
+        }
+        let dir = self.dir.clone().or_else(default_dir)?;
+        Some(ResponseCache::new(dir, Duration::from_secs(self.ttl)))
+    }
+}
+
+/// `$XDG_CACHE_HOME/llm_chat`, falling back to `~/.cache`
+pub fn cache_home() -> Option<PathBuf> {
+    let base = match env::var("XDG_CACHE_HOME") {
+        Ok(value) if !value.is_empty() => PathBuf::from(value),
+        _ => PathBuf::from(env::var("HOME").ok()?).join(".cache"),
+    };
+    Some(base.join("llm_chat"))
+}
+
+/// `responses` in [`cache_home`]
+pub fn default_dir() -> Option<PathBuf> {
+    Some(cache_home()?.join("responses"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[derive(Deserialize, Serialize)]
+struct Cached {
+    /// Unix time in seconds
+    created: u64,
+    reply: Reply,
+}
+
+/// Replies kept on disk, one file per request, named after a hash of the provider
+/// and everything in the request that decides the reply
+#[derive(Clone, Debug)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(dir: PathBuf, ttl: Duration) -> ResponseCache {
+        ResponseCache { dir, ttl }
+    }
+
+    /// Whether the reply is streamed does not change it, so it is not part of the key
+    pub fn key(provider: &str, req: &ChatRequest) -> String {
+        let input = json!({
+            "provider": provider,
+            "model": req.model,
+            "messages": req.messages,
+            "temperature": req.temperature,
+            "max_tokens": req.max_tokens,
+            "tools": req.tools,
+            "json_schema": req.json_schema,
+        });
+        Sha256::digest(input.to_string().as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    /// The stored reply, unless it is older than the ttl or cannot be read
+    pub fn get(&self, key: &str) -> Option<Reply> {
+        let content = fs::read_to_string(self.path(key)).ok()?;
+        let cached: Cached = serde_json::from_str(&content).ok()?;
+        (now().saturating_sub(cached.created) < self.ttl.as_secs()).then_some(cached.reply)
+    }
+
+    pub fn put(&self, key: &str, reply: &Reply) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create cache directory {}", self.dir.display()))?;
+        let cached = Cached {
+            created: now(),
+            reply: reply.clone(),
+        };
+        // Renamed into place so a concurrent reader never sees half a file
+        let tmp = self.dir.join(format!("{key}.{}.tmp", process::id()));
+        fs::write(&tmp, serde_json::to_string(&cached)?)
+            .with_context(|| format!("Failed to write cache file {}", tmp.display()))?;
+        fs::rename(&tmp, self.path(key))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{ChatMessage, Usage};
+
+    #[test]
+    fn replies_are_kept_until_the_ttl() {
+        let dir = env::temp_dir().join(format!("llm_chat_cache_{}", process::id()));
+        let messages = [ChatMessage::new("user", "hi")];
+        let mut req = ChatRequest {
+            model: "m",
+            messages: &messages,
+            stream: true,
+            temperature: None,
+            max_tokens: None,
+            tools: &[],
+            json_schema: None,
+        };
+        let key = ResponseCache::key("groq", &req);
+        req.stream = false;
+        assert_eq!(ResponseCache::key("groq", &req), key);
+        req.temperature = Some(0.5);
+        assert_ne!(ResponseCache::key("groq", &req), key);
+        assert_ne!(ResponseCache::key("ollama", &req), key);
+
+        let reply = Reply {
+            message: ChatMessage::new("assistant", "hello"),
+            usage: Some(Usage {
+                prompt_tokens: 3,
+                completion_tokens: 1,
+            }),
+        };
+        let cache = ResponseCache::new(dir.clone(), DEFAULT_TTL);
+        assert_eq!(cache.get(&key), None);
+        cache.put(&key, &reply).unwrap();
+        assert_eq!(cache.get(&key), Some(reply));
+        assert_eq!(
+            ResponseCache::new(dir.clone(), Duration::ZERO).get(&key),
+            None
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cache::CacheConfig;
+use crate::history::HistoryConfig;
+use crate::provider::ProviderKind;
+use crate::tools::ToolsConfig;
+
+/// Settings read from `$XDG_CONFIG_HOME/llm_chat/config.toml`.
+///
+/// Top level keys apply to every profile, the selected profile overrides them and
+/// command line flags override both:
+///
+/// ```toml
+/// profile = "work"
+/// temperature = 0.7
+/// markdown = true
+///
+/// [profiles.work]
+/// provider = "openai"
+/// base_url = "https://llm.internal.example.com/v1"
+/// model = "gpt-4o"
+/// api_key_env = "WORK_LLM_KEY"
+/// system = "Answer briefly"
+///
+/// [tools]
+/// enabled = ["read_file", "list_dir"]
+///
+/// [prices."gpt-4o"]
+/// prompt = 2.5
+/// completion = 10.0
+///
+/// [cache]
+/// ttl = 86400
+///
+/// [history]
+/// max_age_days = 90
+/// ```
+#[derive(Deserialize, Default, Debug)]
+pub struct FileConfig {
+    /// Profile used when `--profile` is not given
+    pub profile: Option<String>,
+    #[serde(flatten)]
+    pub defaults: Profile,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Tools offered to the model, shared by all profiles
+    #[serde(default)]
+    pub tools: ToolsConfig,
+    /// Prices by model name, used by the `usage` report
+    #[serde(default)]
+    pub prices: BTreeMap<String, Price>,
+    /// Where and for how long replies are cached
+    #[serde(default)]
+    pub cache: CacheConfig,
+    /// Retention and redaction of the history
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// Top level keys that are neither settings nor tables. The flattened defaults
+    /// keep `deny_unknown_fields` from working here, so [`parse`] rejects them.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// Price of a model in dollars per million tokens
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl Price {
+    pub fn cost(&self, prompt_tokens: usize, completion_tokens: usize) -> f64 {
+        (prompt_tokens as f64 * self.prompt + completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub provider: Option<ProviderKind>,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub system: Option<String>,
+    /// Environment variable holding the api key, overrides the provider's default
+    pub api_key_env: Option<String>,
+    /// Render Markdown in replies
+    pub markdown: Option<bool>,
+}
+
+impl Profile {
+    /// Fields set in `other` replace the ones in `self`
+    fn merge(self, other: Profile) -> Profile {
+        Profile {
+            provider: other.provider.or(self.provider),
+            base_url: other.base_url.or(self.base_url),
+            model: other.model.or(self.model),
+            temperature: other.temperature.or(self.temperature),
+            max_tokens: other.max_tokens.or(self.max_tokens),
+            system: other.system.or(self.system),
+            api_key_env: other.api_key_env.or(self.api_key_env),
+            markdown: other.markdown.or(self.markdown),
+        }
+    }
+}
+
+impl FileConfig {
+    /// Settings of the named profile, or of the configured default profile,
+    /// on top of the top level settings
+    pub fn resolve(mut self, name: Option<&str>) -> Result<Profile> {
+        let Some(name) = name.map(str::to_string).or(self.profile.take()) else {
+            return Ok(self.defaults);
+        };
+        let profile = self.profiles.remove(&name).with_context(|| {
+            let known: Vec<&String> = self.profiles.keys().collect();
+            format!("No profile named '{name}' in the config file, known profiles: {known:?}")
+        })?;
+        Ok(self.defaults.merge(profile))
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    let base = match env::var("XDG_CONFIG_HOME") {
+        Ok(value) if !value.is_empty() => PathBuf::from(value),
+        _ => PathBuf::from(env::var("HOME").ok()?).join(".config"),
+    };
+    Some(base.join("llm_chat").join("config.toml"))
+}
+
+/// Reads the config file, a missing file is treated as an empty config
+pub fn load(path: &Path) -> Result<FileConfig> {
+    if !path.exists() {
+        return Ok(FileConfig::default());
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    parse(&content).with_context(|| format!("Failed to parse config file {}", path.display()))
+}
+
+pub fn parse(content: &str) -> Result<FileConfig> {
+    let config: FileConfig = toml::from_str(content)?;
+    if let Some(key) = config.unknown.keys().next() {
+        bail!("unknown field `{key}` at the top level");
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+profile = "local"
+temperature = 0.5
+model = "top-level"
+
+[profiles.local]
+provider = "ollama"
+model = "llama3.2"
+
+[profiles.work]
+provider = "openai"
+base_url = "http://localhost:8080/v1"
+api_key_env = "WORK_KEY"
+max_tokens = 100
+
+[tools]
+enabled = ["run_command"]
+allowed_commands = ["git"]
+
+[prices."llama3.2"]
+prompt = 0.5
+completion = 1.5
+"#;
+
+    #[test]
+    fn profile_overrides_top_level() {
+        let config: FileConfig = toml::from_str(CONFIG).unwrap();
+        let profile = config.resolve(None).unwrap();
+        assert_eq!(profile.provider, Some(ProviderKind::Ollama));
+        assert_eq!(profile.model.as_deref(), Some("llama3.2"));
+        assert_eq!(profile.temperature, Some(0.5));
+
+        let config: FileConfig = toml::from_str(CONFIG).unwrap();
+        assert_eq!(config.tools.allowed_commands, ["git"]);
+        assert_eq!(config.tools.max_rounds, 8);
+        assert_eq!(config.prices["llama3.2"].cost(2_000_000, 1_000_000), 2.5);
+
+        let config: FileConfig = toml::from_str(CONFIG).unwrap();
+        let profile = config.resolve(Some("work")).unwrap();
+        assert_eq!(profile.model.as_deref(), Some("top-level"));
+        assert_eq!(profile.api_key_env.as_deref(), Some("WORK_KEY"));
+        assert_eq!(profile.max_tokens, Some(100));
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        let config: FileConfig = toml::from_str(CONFIG).unwrap();
+        assert!(config.resolve(Some("missing")).is_err());
+        assert!(toml::from_str::<FileConfig>("[profiles.x]\nmodle = \"typo\"").is_err());
+    }
+
+    #[test]
+    fn unknown_top_level_key_is_an_error() {
+        assert!(parse(CONFIG).is_ok());
+        let err = parse("modle = \"typo\"").unwrap_err();
+        assert_eq!(err.to_string(), "unknown field `modle` at the top level");
+    }
+}
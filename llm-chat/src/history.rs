@@ -0,0 +1,353 @@
+mod json;
+mod sqlite;
+
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::estimate_tokens;
+use crate::provider::{ChatMessage, Usage};
+use crate::redact::Redactor;
+pub use json::{JsonData, JsonStore};
+pub use sqlite::SqliteStore;
+
+pub const DEFAULT_SESSION: &str = "default";
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub prompt: String,
+    pub response: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Unix time in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    /// Tokens billed for the prompt, including system prompt, context and tool rounds.
+    /// Estimated from the prompt alone when the api did not report usage.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_tokens: Option<usize>,
+    /// Tool calls made while answering and their results, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_log: Vec<ChatMessage>,
+    /// The response was reused from the cache, no tokens were billed for it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+}
+
+impl Entry {
+    /// Creates an entry stamped with the current time and estimated token counts
+    pub fn new(prompt: String, response: String, model: &str) -> Entry {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .ok();
+        Entry {
+            prompt_tokens: Some(estimate_tokens(&prompt)),
+            response_tokens: Some(estimate_tokens(&response)),
+            prompt,
+            response,
+            model: Some(model.to_string()),
+            timestamp,
+            tool_log: vec![],
+            cached: false,
+        }
+    }
+
+    /// Replaces the estimated token counts with the ones reported by the api
+    pub fn with_usage(mut self, usage: Option<Usage>) -> Entry {
+        if let Some(usage) = usage {
+            self.prompt_tokens = Some(usage.prompt_tokens as usize);
+            self.response_tokens = Some(usage.completion_tokens as usize);
+        }
+        self
+    }
+
+    /// Day the entry was made on in the local timezone
+    pub fn local_day(&self) -> Option<NaiveDate> {
+        let time = DateTime::from_timestamp(self.timestamp?, 0)?;
+        Some(time.with_timezone(&Local).date_naive())
+    }
+}
+
+/// A named conversation with its own model and system prompt
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct Session {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(default)]
+    pub chatlog: Vec<Entry>,
+}
+
+pub struct SessionSummary {
+    pub name: String,
+    pub entries: usize,
+    pub model: Option<String>,
+}
+
+pub struct SearchHit {
+    pub session: String,
+    pub entry: Entry,
+}
+
+/// The `[history]` table of the config file, how long entries are kept and which
+/// secrets are removed from them before they are saved:
+///
+/// ```toml
+/// [history]
+/// max_entries = 500
+/// max_age_days = 90
+/// redact = ["internal-[0-9a-f]{32}"]
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Entries kept per session, the oldest are pruned first
+    pub max_entries: Option<usize>,
+    /// Days an entry is kept
+    pub max_age_days: Option<u64>,
+    /// Redact api keys and email addresses
+    #[serde(default = "default_redact_defaults")]
+    pub redact_defaults: bool,
+    /// Regexes whose matches are redacted as well
+    #[serde(default)]
+    pub redact: Vec<String>,
+}
+
+fn default_redact_defaults() -> bool {
+    true
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_entries: None,
+            max_age_days: None,
+            redact_defaults: default_redact_defaults(),
+            redact: vec![],
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn policy(&self) -> Result<Policy> {
+        Ok(Policy {
+            retention: Retention {
+                max_entries: self.max_entries,
+                max_age: self
+                    .max_age_days
+                    .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            },
+            redactor: Redactor::new(self.redact_defaults, &self.redact)?,
+        })
+    }
+}
+
+/// Which entries are pruned, nothing is pruned when neither limit is set
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// Entries kept per session
+    pub max_entries: Option<usize>,
+    /// Entries older than this are pruned, entries without a timestamp are kept
+    pub max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_entries.is_none() && self.max_age.is_none()
+    }
+
+    /// Unix time before which entries are pruned
+    pub fn cutoff(&self) -> Option<i64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        Some(now.saturating_sub(self.max_age?).as_secs() as i64)
+    }
+}
+
+/// How entries are saved: redacted, then the session is pruned
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    pub retention: Retention,
+    pub redactor: Redactor,
+}
+
+impl Policy {
+    pub fn save(&self, store: &mut dyn HistoryStore, session: &str, entry: &Entry) -> Result<()> {
+        self.append(store, session, entry)?;
+        self.prune(store)
+    }
+
+    /// Appends the redacted entry without pruning, for adding many entries at once
+    pub fn append(&self, store: &mut dyn HistoryStore, session: &str, entry: &Entry) -> Result<()> {
+        store.append(session, &self.redactor.entry(entry))
+    }
+
+    pub fn prune(&self, store: &mut dyn HistoryStore) -> Result<()> {
+        if !self.retention.is_unlimited() {
+            store.prune(&self.retention)?;
+        }
+        Ok(())
+    }
+}
+
+/// Storage for sessions and their chat logs
+pub trait HistoryStore {
+    /// Session used when `--session` is not given
+    fn current(&self) -> Result<Option<String>>;
+
+    fn session(&self, name: &str) -> Result<Option<Session>>;
+
+    fn list(&self) -> Result<Vec<SessionSummary>>;
+
+    /// Appends an entry to the session, creating the session if needed
+    fn append(&mut self, session: &str, entry: &Entry) -> Result<()>;
+
+    /// Replaces the model and system prompt of the session, creating the session if needed
+    fn set_settings(&mut self, name: &str, model: Option<&str>, system: Option<&str>)
+        -> Result<()>;
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()>;
+
+    fn delete(&mut self, name: &str) -> Result<()>;
+
+    /// Copies a session, including its history, under a new name
+    fn fork(&mut self, from: &str, to: &str) -> Result<()>;
+
+    /// Makes `name` the session used when `--session` is not given
+    fn resume(&mut self, name: &str) -> Result<()>;
+
+    /// Entries matching all of the search terms, newest first
+    fn search(&self, terms: &str, limit: usize) -> Result<Vec<SearchHit>>;
+
+    /// Removes the entries the retention policy does not keep, returning how many
+    fn prune(&mut self, retention: &Retention) -> Result<usize>;
+
+    /// Name of the session to use, falling back to the resumed one and then the default
+    fn resolve_name(&self, name: Option<&str>) -> Result<String> {
+        Ok(match name {
+            Some(name) => name.to_string(),
+            None => self
+                .current()?
+                .unwrap_or_else(|| DEFAULT_SESSION.to_string()),
+        })
+    }
+}
+
+/// Opens the store for `path`, `.json` files use the JSON store and anything else SQLite
+pub fn open(path: &Path) -> Result<Box<dyn HistoryStore>> {
+    if path.extension().is_some_and(|ext| ext == "json") {
+        Ok(Box::new(JsonStore::open(path)?))
+    } else {
+        Ok(Box::new(SqliteStore::open(path)?))
+    }
+}
+
+/// Copies every session, its settings and entries from one store into another
+pub fn migrate(from: &dyn HistoryStore, to: &mut dyn HistoryStore, policy: &Policy) -> Result<()> {
+    for summary in from.list()? {
+        let Some(session) = from.session(&summary.name)? else {
+            continue;
+        };
+        to.set_settings(
+            &summary.name,
+            session.model.as_deref(),
+            session.system.as_deref(),
+        )?;
+        for entry in &session.chatlog {
+            policy.append(to, &summary.name, entry)?;
+        }
+    }
+    if let Some(current) = from.current()? {
+        to.resume(&current)?;
+    }
+    policy.prune(to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(prompt: &str, response: &str) -> Entry {
+        Entry::new(prompt.to_string(), response.to_string(), "test-model")
+    }
+
+    fn exercise(store: &mut dyn HistoryStore) {
+        // Built once, entries are stamped with the current second
+        let traits = entry("rust traits", "are interfaces");
+        store.append("work", &entry("hi", "hello")).unwrap();
+        store.append("work", &traits).unwrap();
+        store.set_settings("work", Some("m"), None).unwrap();
+        store.resume("work").unwrap();
+
+        store.rename("work", "job").unwrap();
+        assert_eq!(store.resolve_name(None).unwrap(), "job");
+        store.fork("job", "copy").unwrap();
+        assert!(store.fork("job", "copy").is_err());
+        let copy = store.session("copy").unwrap().unwrap();
+        assert_eq!(copy.model.as_deref(), Some("m"));
+        assert_eq!(copy.chatlog[1], traits);
+
+        let hits = store.search("Interfaces RUST", 10).unwrap();
+        let sessions: Vec<&str> = hits.iter().map(|hit| hit.session.as_str()).collect();
+        assert_eq!(hits.len(), 2);
+        assert!(sessions.contains(&"job") && sessions.contains(&"copy"));
+
+        store.delete("job").unwrap();
+        assert_eq!(store.resolve_name(None).unwrap(), DEFAULT_SESSION);
+        assert!(store.delete("job").is_err());
+        assert_eq!(store.search("interfaces", 10).unwrap().len(), 1);
+
+        let names: Vec<String> = store.list().unwrap().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, ["copy"]);
+
+        let mut old = entry("old", "entry");
+        old.timestamp = Some(0);
+        store.append("copy", &old).unwrap();
+        let retention = Retention {
+            max_entries: Some(1),
+            max_age: Some(Duration::from_secs(60)),
+        };
+        assert_eq!(store.prune(&retention).unwrap(), 2);
+        let copy = store.session("copy").unwrap().unwrap();
+        assert_eq!(copy.chatlog, [traits]);
+        assert_eq!(store.prune(&retention).unwrap(), 0);
+    }
+
+    #[test]
+    fn json_store() {
+        let path = std::env::temp_dir().join(format!("llm_chat_store_{}.json", std::process::id()));
+        let mut store = JsonStore::open(&path).unwrap();
+        exercise(&mut store);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sqlite_store() {
+        exercise(&mut SqliteStore::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn migrate_json_to_sqlite() {
+        let path =
+            std::env::temp_dir().join(format!("llm_chat_migrate_{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"current":"a","sessions":{"a":{"system":"s","chatlog":[{"prompt":"p","response":"r"}]}}}"#,
+        )
+        .unwrap();
+        let json = JsonStore::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut sqlite = SqliteStore::open_in_memory().unwrap();
+        migrate(&json, &mut sqlite, &Policy::default()).unwrap();
+        assert_eq!(sqlite.current().unwrap().as_deref(), Some("a"));
+        let session = sqlite.session("a").unwrap().unwrap();
+        assert_eq!(session.system.as_deref(), Some("s"));
+        assert_eq!(session.chatlog[0].prompt, "p");
+    }
+}
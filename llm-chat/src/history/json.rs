@@ -0,0 +1,223 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use super::{Entry, HistoryStore, Retention, SearchHit, Session, SessionSummary, DEFAULT_SESSION};
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct JsonData {
+    /// Entries written before sessions existed, moved into the default session on load
+    #[serde(default, skip_serializing)]
+    chatlog: Vec<Entry>,
+    /// Session used when `--session` is not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<String>,
+    #[serde(default)]
+    pub sessions: BTreeMap<String, Session>,
+}
+
+impl JsonData {
+    /// Reads the history file, a missing file is treated as empty history
+    pub fn load(path: &Path) -> Result<JsonData> {
+        if !path.exists() {
+            return Ok(JsonData::default());
+        }
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open history file {}", path.display()))?;
+        let reader = BufReader::new(file);
+        let mut data: JsonData =
+            serde_json::from_reader(reader).with_context(|| "Couldn't Deserialize data")?;
+
+        if !data.chatlog.is_empty() {
+            let legacy = std::mem::take(&mut data.chatlog);
+            let session = data
+                .sessions
+                .entry(DEFAULT_SESSION.to_string())
+                .or_default();
+            session.chatlog.splice(0..0, legacy);
+        }
+        Ok(data)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to write history file {}", path.display()))?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+/// History kept in a single JSON file that is rewritten on every change
+pub struct JsonStore {
+    path: PathBuf,
+    data: JsonData,
+}
+
+impl JsonStore {
+    pub fn open(path: &Path) -> Result<JsonStore> {
+        Ok(JsonStore {
+            path: path.to_path_buf(),
+            data: JsonData::load(path)?,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        self.data.save(&self.path)
+    }
+}
+
+impl HistoryStore for JsonStore {
+    fn current(&self) -> Result<Option<String>> {
+        Ok(self.data.current.clone())
+    }
+
+    fn session(&self, name: &str) -> Result<Option<Session>> {
+        Ok(self.data.sessions.get(name).cloned())
+    }
+
+    fn list(&self) -> Result<Vec<SessionSummary>> {
+        Ok(self
+            .data
+            .sessions
+            .iter()
+            .map(|(name, session)| SessionSummary {
+                name: name.clone(),
+                entries: session.chatlog.len(),
+                model: session.model.clone(),
+            })
+            .collect())
+    }
+
+    fn append(&mut self, session: &str, entry: &Entry) -> Result<()> {
+        let session = self.data.sessions.entry(session.to_string()).or_default();
+        session.chatlog.push(entry.clone());
+        self.save()
+    }
+
+    fn set_settings(
+        &mut self,
+        name: &str,
+        model: Option<&str>,
+        system: Option<&str>,
+    ) -> Result<()> {
+        let session = self.data.sessions.entry(name.to_string()).or_default();
+        session.model = model.map(str::to_string);
+        session.system = system.map(str::to_string);
+        self.save()
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        if self.data.sessions.contains_key(to) {
+            bail!("Session '{to}' already exists");
+        }
+        let session = self
+            .data
+            .sessions
+            .remove(from)
+            .with_context(|| format!("No session named '{from}'"))?;
+        self.data.sessions.insert(to.to_string(), session);
+        if self.data.current.as_deref() == Some(from) {
+            self.data.current = Some(to.to_string());
+        }
+        self.save()
+    }
+
+    fn delete(&mut self, name: &str) -> Result<()> {
+        if self.data.sessions.remove(name).is_none() {
+            bail!("No session named '{name}'");
+        }
+        if self.data.current.as_deref() == Some(name) {
+            self.data.current = None;
+        }
+        self.save()
+    }
+
+    fn fork(&mut self, from: &str, to: &str) -> Result<()> {
+        if self.data.sessions.contains_key(to) {
+            bail!("Session '{to}' already exists");
+        }
+        let session = self
+            .data
+            .sessions
+            .get(from)
+            .with_context(|| format!("No session named '{from}'"))?
+            .clone();
+        self.data.sessions.insert(to.to_string(), session);
+        self.save()
+    }
+
+    fn resume(&mut self, name: &str) -> Result<()> {
+        if !self.data.sessions.contains_key(name) {
+            bail!("No session named '{name}'");
+        }
+        self.data.current = Some(name.to_string());
+        self.save()
+    }
+
+    fn search(&self, terms: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        let terms: Vec<String> = terms.split_whitespace().map(str::to_lowercase).collect();
+        let mut hits: Vec<SearchHit> = self
+            .data
+            .sessions
+            .iter()
+            .flat_map(|(name, session)| {
+                session.chatlog.iter().map(move |entry| SearchHit {
+                    session: name.clone(),
+                    entry: entry.clone(),
+                })
+            })
+            .filter(|hit| {
+                let text = format!("{}\n{}", hit.entry.prompt, hit.entry.response).to_lowercase();
+                terms.iter().all(|term| text.contains(term))
+            })
+            .collect();
+        hits.sort_by_key(|hit| std::cmp::Reverse(hit.entry.timestamp));
+        hits.truncate(limit);
+        Ok(hits)
+    }
+
+    fn prune(&mut self, retention: &Retention) -> Result<usize> {
+        let cutoff = retention.cutoff();
+        let mut pruned = 0;
+        for session in self.data.sessions.values_mut() {
+            let before = session.chatlog.len();
+            if let Some(cutoff) = cutoff {
+                session
+                    .chatlog
+                    .retain(|entry| entry.timestamp.is_none_or(|time| time >= cutoff));
+            }
+            if let Some(max) = retention.max_entries {
+                let excess = session.chatlog.len().saturating_sub(max);
+                session.chatlog.drain(..excess);
+            }
+            pruned += before - session.chatlog.len();
+        }
+        if pruned > 0 {
+            self.save()?;
+        }
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_chatlog_moves_to_default_session() {
+        let path =
+            std::env::temp_dir().join(format!("llm_chat_legacy_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"chatlog":[{"prompt":"hi","response":"hello"}]}"#).unwrap();
+
+        let data = JsonData::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.sessions[DEFAULT_SESSION].chatlog.len(), 1);
+        assert!(serde_json::to_string(&data)
+            .unwrap()
+            .starts_with(r#"{"sessions""#));
+    }
+}
@@ -0,0 +1,361 @@
+use anyhow::{bail, Context, Result};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::path::Path;
+use std::time::Duration;
+
+use super::{Entry, HistoryStore, Retention, SearchHit, Session, SessionSummary};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    name   TEXT PRIMARY KEY,
+    model  TEXT,
+    system TEXT
+);
+CREATE TABLE IF NOT EXISTS entries (
+    id              INTEGER PRIMARY KEY,
+    session         TEXT NOT NULL REFERENCES sessions(name) ON UPDATE CASCADE ON DELETE CASCADE,
+    prompt          TEXT NOT NULL,
+    response        TEXT NOT NULL,
+    model           TEXT,
+    created_at      INTEGER,
+    prompt_tokens   INTEGER,
+    response_tokens INTEGER,
+    tool_log        TEXT,
+    cached          INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS entries_session ON entries(session);
+CREATE TABLE IF NOT EXISTS state (
+    key   TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE VIRTUAL TABLE IF NOT EXISTS entries_fts USING fts5(
+    prompt, response, content='entries', content_rowid='id'
+);
+CREATE TRIGGER IF NOT EXISTS entries_ai AFTER INSERT ON entries BEGIN
+    INSERT INTO entries_fts(rowid, prompt, response) VALUES (new.id, new.prompt, new.response);
+END;
+CREATE TRIGGER IF NOT EXISTS entries_ad AFTER DELETE ON entries BEGIN
+    INSERT INTO entries_fts(entries_fts, rowid, prompt, response)
+    VALUES ('delete', old.id, old.prompt, old.response);
+END;
+";
+
+/// Columns added after the first release, as `(user_version, statement)`
+const MIGRATIONS: &[(i32, &str)] = &[];
+
+const ENTRY_COLUMNS: &str =
+    "prompt, response, model, created_at, prompt_tokens, response_tokens, tool_log, cached";
+
+/// History kept in a SQLite database with full-text search over prompts and responses
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<SqliteStore> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open history database {}", path.display()))?;
+        Self::init(conn)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteStore> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<SqliteStore> {
+        // Concurrent invocations wait for each other instead of failing
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        let existing = conn
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE name = 'entries'",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        conn.execute_batch(SCHEMA)
+            .with_context(|| "Failed to create the history tables")?;
+        // New databases get the current schema, older ones are brought up to date
+        if existing {
+            for (target, statement) in MIGRATIONS.iter().filter(|(v, _)| *v > version) {
+                conn.execute_batch(statement).with_context(|| {
+                    format!("Failed to migrate the history to version {target}")
+                })?;
+            }
+        }
+        let latest = MIGRATIONS.last().map_or(0, |(v, _)| *v);
+        conn.pragma_update(None, "user_version", latest)?;
+        Ok(SqliteStore { conn })
+    }
+
+    fn exists(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row("SELECT 1 FROM sessions WHERE name = ?1", [name], |_| Ok(()))
+            .optional()?
+            .is_some())
+    }
+}
+
+fn entry_from_row(row: &Row, offset: usize) -> rusqlite::Result<Entry> {
+    let tool_log = match row.get::<_, Option<String>>(offset + 6)? {
+        Some(json) => serde_json::from_str(&json).map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(offset + 6, Type::Text, Box::new(err))
+        })?,
+        None => vec![],
+    };
+    Ok(Entry {
+        prompt: row.get(offset)?,
+        response: row.get(offset + 1)?,
+        model: row.get(offset + 2)?,
+        timestamp: row.get(offset + 3)?,
+        prompt_tokens: row.get(offset + 4)?,
+        response_tokens: row.get(offset + 5)?,
+        tool_log,
+        cached: row.get(offset + 7)?,
+    })
+}
+
+/// Turns free text into an FTS5 query matching every term, quoting each term so
+/// characters like `-` or `:` are not read as query syntax
+fn fts_query(terms: &str) -> String {
+    terms
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl HistoryStore for SqliteStore {
+    fn current(&self) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row("SELECT value FROM state WHERE key = 'current'", [], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn session(&self, name: &str) -> Result<Option<Session>> {
+        let Some((model, system)) = self
+            .conn
+            .query_row(
+                "SELECT model, system FROM sessions WHERE name = ?1",
+                [name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {ENTRY_COLUMNS} FROM entries WHERE session = ?1 ORDER BY id"
+        ))?;
+        let chatlog = stmt
+            .query_map([name], |row| entry_from_row(row, 0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(Some(Session {
+            model,
+            system,
+            chatlog,
+        }))
+    }
+
+    fn list(&self) -> Result<Vec<SessionSummary>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT s.name, s.model, COUNT(e.id) FROM sessions s
+             LEFT JOIN entries e ON e.session = s.name
+             GROUP BY s.name ORDER BY s.name",
+        )?;
+        let sessions = stmt
+            .query_map([], |row| {
+                Ok(SessionSummary {
+                    name: row.get(0)?,
+                    model: row.get(1)?,
+                    entries: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(sessions)
+    }
+
+    fn append(&mut self, session: &str, entry: &Entry) -> Result<()> {
+        let tool_log = (!entry.tool_log.is_empty())
+            .then(|| serde_json::to_string(&entry.tool_log))
+            .transpose()?;
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO sessions (name) VALUES (?1)",
+            [session],
+        )?;
+        tx.execute(
+            &format!("INSERT INTO entries (session, {ENTRY_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"),
+            params![
+                session,
+                entry.prompt,
+                entry.response,
+                entry.model,
+                entry.timestamp,
+                entry.prompt_tokens,
+                entry.response_tokens,
+                tool_log,
+                entry.cached,
+            ],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn set_settings(
+        &mut self,
+        name: &str,
+        model: Option<&str>,
+        system: Option<&str>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO sessions (name, model, system) VALUES (?1, ?2, ?3)
+             ON CONFLICT(name) DO UPDATE SET model = excluded.model, system = excluded.system",
+            params![name, model, system],
+        )?;
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        if self.exists(to)? {
+            bail!("Session '{to}' already exists");
+        }
+        let tx = self.conn.transaction()?;
+        if tx.execute("UPDATE sessions SET name = ?2 WHERE name = ?1", [from, to])? == 0 {
+            bail!("No session named '{from}'");
+        }
+        tx.execute(
+            "UPDATE state SET value = ?2 WHERE key = 'current' AND value = ?1",
+            [from, to],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete(&mut self, name: &str) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        if tx.execute("DELETE FROM sessions WHERE name = ?1", [name])? == 0 {
+            bail!("No session named '{name}'");
+        }
+        tx.execute(
+            "DELETE FROM state WHERE key = 'current' AND value = ?1",
+            [name],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn fork(&mut self, from: &str, to: &str) -> Result<()> {
+        if self.exists(to)? {
+            bail!("Session '{to}' already exists");
+        }
+        let tx = self.conn.transaction()?;
+        if tx.execute(
+            "INSERT INTO sessions (name, model, system)
+             SELECT ?2, model, system FROM sessions WHERE name = ?1",
+            [from, to],
+        )? == 0
+        {
+            bail!("No session named '{from}'");
+        }
+        tx.execute(
+            &format!(
+                "INSERT INTO entries (session, {ENTRY_COLUMNS})
+                 SELECT ?2, {ENTRY_COLUMNS} FROM entries WHERE session = ?1 ORDER BY id"
+            ),
+            [from, to],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn resume(&mut self, name: &str) -> Result<()> {
+        if !self.exists(name)? {
+            bail!("No session named '{name}'");
+        }
+        self.conn.execute(
+            "INSERT INTO state (key, value) VALUES ('current', ?1)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            [name],
+        )?;
+        Ok(())
+    }
+
+    fn search(&self, terms: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        let query = fts_query(terms);
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT session, {ENTRY_COLUMNS} FROM entries
+             WHERE id IN (SELECT rowid FROM entries_fts WHERE entries_fts MATCH ?1)
+             ORDER BY id DESC LIMIT ?2"
+        ))?;
+        let hits = stmt
+            .query_map(params![query, limit], |row| {
+                Ok(SearchHit {
+                    session: row.get(0)?,
+                    entry: entry_from_row(row, 1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(hits)
+    }
+
+    fn prune(&mut self, retention: &Retention) -> Result<usize> {
+        // Pruned entries may hold secrets, so their pages are zeroed rather than freed
+        self.conn.pragma_update(None, "secure_delete", true)?;
+        let tx = self.conn.transaction()?;
+        let mut pruned = 0;
+        if let Some(cutoff) = retention.cutoff() {
+            pruned += tx.execute("DELETE FROM entries WHERE created_at < ?1", [cutoff])?;
+        }
+        if let Some(max) = retention.max_entries {
+            pruned += tx.execute(
+                "DELETE FROM entries WHERE id IN (
+                     SELECT id FROM (
+                         SELECT id, ROW_NUMBER() OVER (PARTITION BY session ORDER BY id DESC) AS newer
+                         FROM entries
+                     ) WHERE newer > ?1
+                 )",
+                [max],
+            )?;
+        }
+        tx.commit()?;
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fts_query_quotes_terms() {
+        assert_eq!(fts_query("rust  -lang"), r#""rust" "-lang""#);
+        assert_eq!(fts_query(r#"say "hi""#), r#""say" """hi""""#);
+    }
+
+    #[test]
+    fn tool_log_and_cached_are_stored() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let mut entry = Entry::new("ls".to_string(), "done".to_string(), "m");
+        entry.tool_log = vec![crate::provider::ChatMessage::tool_result(
+            "call_1",
+            "a\nb".to_string(),
+        )];
+        entry.cached = true;
+        store.append("s", &entry).unwrap();
+        let plain = Entry::new("hi".to_string(), "hello".to_string(), "m");
+        store.append("s", &plain).unwrap();
+        assert_eq!(store.session("s").unwrap().unwrap().chatlog, [entry, plain]);
+    }
+}
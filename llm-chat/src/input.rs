@@ -0,0 +1,126 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::Path;
+
+/// Text sent along with the prompt, from stdin or a file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attachment {
+    pub name: String,
+    pub content: String,
+}
+
+/// Reads piped stdin and the given files, failing if together they exceed `max_bytes`
+pub fn read_attachments(
+    files: &[String],
+    read_stdin: bool,
+    max_bytes: usize,
+) -> Result<Vec<Attachment>> {
+    let mut attachments = vec![];
+    let mut total = 0;
+
+    if read_stdin && !io::stdin().is_terminal() {
+        let mut buf = vec![];
+        io::stdin()
+            .take(max_bytes as u64 + 1)
+            .read_to_end(&mut buf)
+            .with_context(|| "Failed to read stdin")?;
+        total += buf.len();
+        check_size(total, max_bytes)?;
+        let content = String::from_utf8(buf).with_context(|| "stdin is not valid UTF-8 text")?;
+        if !content.trim().is_empty() {
+            attachments.push(Attachment {
+                name: "stdin".to_string(),
+                content,
+            });
+        }
+    }
+
+    for file in files {
+        let path = Path::new(file);
+        let len = fs::metadata(path)
+            .with_context(|| format!("Failed to read {file}"))?
+            .len();
+        total += len as usize;
+        check_size(total, max_bytes)?;
+        let content = fs::read_to_string(path).with_context(|| {
+            format!("Failed to read {file}, only UTF-8 text files can be attached")
+        })?;
+        attachments.push(Attachment {
+            name: file.clone(),
+            content,
+        });
+    }
+    Ok(attachments)
+}
+
+fn check_size(total: usize, max_bytes: usize) -> Result<()> {
+    if total > max_bytes {
+        bail!(
+            "Attached input is larger than the limit of {max_bytes} bytes, \
+             raise it with --max-input-bytes or send less"
+        );
+    }
+    Ok(())
+}
+
+/// Appends every attachment to the prompt inside a code fence labelled with its name
+pub fn compose(prompt: &str, attachments: &[Attachment]) -> String {
+    let mut out = prompt.trim().to_string();
+    for attachment in attachments {
+        // The fence has to be longer than any backtick run inside the content
+        let longest_run = attachment
+            .content
+            .split(|c| c != '`')
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        let fence = "`".repeat(longest_run.max(2) + 1);
+        if !out.is_empty() {
+            out.push_str("\n\n");
+        }
+        out.push_str(&format!(
+            "{fence}{}\n{}\n{fence}",
+            attachment.name,
+            attachment.content.trim_end_matches('\n')
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachments_are_fenced() {
+        let attachments = vec![
+            Attachment {
+                name: "stdin".to_string(),
+                content: "diff\n".to_string(),
+            },
+            Attachment {
+                name: "README.md".to_string(),
+                content: "```rust\nfn main() {}\n```".to_string(),
+            },
+        ];
+        assert_eq!(
+            compose("review this", &attachments),
+            "review this\n\n```stdin\ndiff\n```\n\n\
+             ````README.md\n```rust\nfn main() {}\n```\n````"
+        );
+        assert_eq!(compose("", &attachments[..1]), "```stdin\ndiff\n```");
+    }
+
+    #[test]
+    fn size_limit() {
+        let path = std::env::temp_dir().join(format!("llm_chat_input_{}.txt", std::process::id()));
+        std::fs::write(&path, "0123456789").unwrap();
+        let files = vec![path.to_string_lossy().to_string()];
+        let result = read_attachments(&files, false, 5);
+        let ok = read_attachments(&files, false, 10);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().to_string().contains("limit of 5 bytes"));
+        assert_eq!(ok.unwrap()[0].content, "0123456789");
+    }
+}
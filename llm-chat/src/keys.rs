@@ -0,0 +1,275 @@
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Read instead of asking for the passphrase, for scripts
+pub const PASSPHRASE_ENV: &str = "LLM_CHAT_PASSPHRASE";
+
+/// The key store, `keys.json` next to the config file
+pub fn store_path(config_path: &Path) -> PathBuf {
+    config_path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join("keys.json")
+}
+
+/// Asks for the passphrase on the terminal unless it is in the environment
+pub fn passphrase(prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if !io::stdin().is_terminal() {
+        bail!("The api key store needs a passphrase, set {PASSPHRASE_ENV} or run in a terminal");
+    }
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+/// Asks twice for the passphrase of a new store unless it is in the environment
+pub fn new_passphrase() -> Result<String> {
+    let passphrase = passphrase("New passphrase for the api key store: ")?;
+    if env::var(PASSPHRASE_ENV).is_err()
+        && rpassword::prompt_password("Repeat the passphrase: ")? != passphrase
+    {
+        bail!("The passphrases do not match");
+    }
+    if passphrase.is_empty() {
+        bail!("The passphrase is empty");
+    }
+    Ok(passphrase)
+}
+
+/// Reads the key to store, hidden on a terminal or as the first line of stdin,
+/// so it never shows up in the shell history or `ps`
+pub fn read_key(name: &str) -> Result<String> {
+    let key = if io::stdin().is_terminal() {
+        rpassword::prompt_password(format!("Api key for {name}: "))?
+    } else {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        line
+    };
+    let key = key.trim();
+    if key.is_empty() {
+        bail!("No api key was given");
+    }
+    Ok(key.to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        bail!("odd number of hex digits");
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).context("invalid hex digit"))
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum StoredKey {
+    /// Encrypted with a key derived from the passphrase, both fields hex encoded
+    Encrypted { nonce: String, ciphertext: String },
+    /// Command that prints the key, like `pass show groq`
+    Command(String),
+}
+
+/// Api keys by provider, encrypted with one passphrase or read from a command.
+/// The file only ever holds ciphertext and commands.
+#[derive(Serialize, Deserialize, Default)]
+pub struct KeyStore {
+    /// Salt of the passphrase, hex encoded
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    salt: String,
+    #[serde(default)]
+    keys: BTreeMap<String, StoredKey>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl KeyStore {
+    /// Reads the store, a missing file is an empty store
+    pub fn open(path: &Path) -> Result<KeyStore> {
+        let mut store = if path.exists() {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read key store {}", path.display()))?;
+            serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse key store {}", path.display()))?
+        } else {
+            KeyStore::default()
+        };
+        store.path = path.to_path_buf();
+        Ok(store)
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options
+            .open(&self.path)
+            .with_context(|| format!("Failed to write key store {}", self.path.display()))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Whether any key is encrypted, a new passphrase has to match theirs
+    pub fn has_encrypted(&self) -> bool {
+        self.keys
+            .values()
+            .any(|key| matches!(key, StoredKey::Encrypted { .. }))
+    }
+
+    fn cipher(&self, passphrase: &str) -> Result<ChaCha20Poly1305> {
+        let salt = unhex(&self.salt).context("Corrupt key store salt")?;
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|err| anyhow!("Failed to derive the key store key: {err}"))?;
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    fn decrypt(cipher: &ChaCha20Poly1305, nonce: &str, ciphertext: &str) -> Result<String> {
+        let nonce = unhex(nonce).context("Corrupt key store nonce")?;
+        if nonce.len() != 12 {
+            bail!("Corrupt key store nonce");
+        }
+        let plain = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                unhex(ciphertext)
+                    .context("Corrupt key store entry")?
+                    .as_slice(),
+            )
+            .map_err(|_| anyhow!("Wrong passphrase for the api key store"))?;
+        String::from_utf8(plain).context("Corrupt key store entry")
+    }
+
+    /// Encrypts and stores the key. Every key shares one passphrase, so it has to
+    /// open the keys already stored.
+    pub fn set_key(&mut self, name: &str, key: &str, passphrase: &str) -> Result<()> {
+        if self.salt.is_empty() || !self.has_encrypted() {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            self.salt = hex(&salt);
+        }
+        let cipher = self.cipher(passphrase)?;
+        if let Some(StoredKey::Encrypted { nonce, ciphertext }) = self
+            .keys
+            .values()
+            .find(|key| matches!(key, StoredKey::Encrypted { .. }))
+        {
+            KeyStore::decrypt(&cipher, nonce, ciphertext)?;
+        }
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, key.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt the api key"))?;
+        self.keys.insert(
+            name.to_string(),
+            StoredKey::Encrypted {
+                nonce: hex(&nonce),
+                ciphertext: hex(&ciphertext),
+            },
+        );
+        self.save()
+    }
+
+    pub fn set_command(&mut self, name: &str, command: &str) -> Result<()> {
+        self.keys
+            .insert(name.to_string(), StoredKey::Command(command.to_string()));
+        self.save()
+    }
+
+    /// Forgets the key, returns whether there was one
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        let removed = self.keys.remove(name).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// The key for `name`, asking for the passphrase only if it is encrypted
+    pub fn get(
+        &self,
+        name: &str,
+        passphrase: impl FnOnce() -> Result<String>,
+    ) -> Result<Option<String>> {
+        match self.keys.get(name) {
+            None => Ok(None),
+            Some(StoredKey::Encrypted { nonce, ciphertext }) => {
+                let cipher = self.cipher(&passphrase()?)?;
+                KeyStore::decrypt(&cipher, nonce, ciphertext).map(Some)
+            }
+            Some(StoredKey::Command(command)) => run_key_command(name, command).map(Some),
+        }
+    }
+}
+
+/// First line the command prints. Its output is never shown, it holds the key.
+fn run_key_command(name: &str, command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run the key command for {name}"))?;
+    if !output.status.success() {
+        bail!("The key command for {name} failed with {}", output.status);
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .with_context(|| format!("The key command for {name} printed invalid UTF-8"))?;
+    match stdout.lines().next().map(str::trim) {
+        Some(key) if !key.is_empty() => Ok(key.to_string()),
+        _ => bail!("The key command for {name} printed nothing"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_encrypted_with_the_passphrase() {
+        let path = env::temp_dir().join(format!("llm_chat_keys_{}.json", std::process::id()));
+        let mut store = KeyStore::open(&path).unwrap();
+        store.set_key("groq", "gsk_secret", "hunter2").unwrap();
+        store.set_command("openai", "echo sk-from-command").unwrap();
+        assert!(store.set_key("anthropic", "x", "wrong").is_err());
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("gsk_secret"));
+        let store = KeyStore::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let get = |name, passphrase: &str| store.get(name, || Ok(passphrase.to_string()));
+        assert_eq!(
+            get("groq", "hunter2").unwrap().as_deref(),
+            Some("gsk_secret")
+        );
+        assert!(get("groq", "wrong").is_err());
+        assert_eq!(
+            get("openai", "").unwrap().as_deref(),
+            Some("sk-from-command")
+        );
+        assert_eq!(get("ollama", "").unwrap(), None);
+    }
+}
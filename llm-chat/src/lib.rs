@@ -1,174 +1,1019 @@
-use anyhow::{Context, Result};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+pub mod api;
+pub mod archive;
+pub mod cache;
+pub mod config_file;
+pub mod history;
+pub mod input;
+pub mod keys;
+pub mod markdown;
+pub mod provider;
+pub mod rag;
+pub mod redact;
+pub mod repl;
+pub mod schema;
+pub mod serve;
+pub mod stream;
+pub mod template;
+pub mod tools;
+pub mod usage;
+
+use anyhow::{bail, Context, Result};
+use api::{ApiError, HttpClient, RetryPolicy};
+use archive::{DateRange, ExportFormat, ImportFormat};
+use cache::ResponseCache;
+use chrono::{DateTime, Local, NaiveDate};
+use futures_util::StreamExt;
+use history::{Entry, HistoryStore, Session};
+use keys::KeyStore;
+use markdown::Output;
+use provider::{ChatMessage, ChatRequest, Provider, ProviderKind, Reply, Usage};
+use rag::Index;
+use reqwest::Response;
+use schema::JsonSchema;
+use std::collections::BTreeMap;
 use std::fmt::Write;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
-use std::process;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use template::Template;
+use tools::{FunctionCall, ToolsConfig};
 
-const API: &str = "https://api.groq.com/openai/v1/chat/completions";
+/// Replies tried before giving up on one matching `--json-schema`
+const SCHEMA_ATTEMPTS: usize = 3;
 
-#[derive(Serialize)]
-pub struct GroqMessage {
-    role: String,
-    content: String,
+pub struct Config<'a> {
+    /// Model given on the command line, overrides the session's model
+    pub model: Option<String>,
+    /// Model used when neither the command line nor the session pick one
+    pub default_model: String,
+    pub arguments: &'a Vec<String>,
+    /// Piped stdin and files appended to the prompt
+    pub attachments: Vec<input::Attachment>,
+    /// Template the prompt and attachments are filled into
+    pub template: Option<Template>,
+    /// Values for the template's `{{name}}` placeholders
+    pub template_vars: BTreeMap<String, String>,
+    /// Index the chunks sent along with each prompt are retrieved from
+    pub rag: Option<Index>,
+    /// Number of chunks retrieved for each prompt
+    pub rag_top: usize,
+    pub history_filepath: String,
+    /// How entries are redacted and pruned when they are saved
+    pub history_policy: history::Policy,
+    pub session: Option<String>,
+    /// System prompt given on the command line, overrides the session's system prompt
+    pub system: Option<String>,
+    /// System prompt used when neither the command line nor the session set one
+    pub default_system: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub context: usize,
+    pub max_context_tokens: Option<usize>,
+    pub dont_save: bool,
+    pub stream: bool,
+    /// Render Markdown in replies when stdout is a terminal
+    pub markdown: bool,
+    /// Tools the model may call when the provider supports them, answers using tools
+    /// are never streamed
+    pub tools: ToolsConfig,
+    /// Schema the reply must match, the reply is printed as bare JSON
+    pub json_schema: Option<JsonSchema>,
+    /// Prices by model name for the usage report
+    pub prices: BTreeMap<String, config_file::Price>,
+    /// Cache replies are looked up in and stored to, `None` with `--no-cache`
+    pub cache: Option<ResponseCache>,
+    /// Longest wait for data from the api, `None` waits forever
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Duration,
+    pub retry: RetryPolicy,
 }
 
-#[derive(Serialize)]
-pub struct GroqRequest {
-    model: String,
-    messages: Vec<GroqMessage>,
+impl Config<'_> {
+    fn history_path(&self) -> &Path {
+        Path::new(&self.history_filepath)
+    }
+
+    pub fn http_client(&self) -> Result<HttpClient> {
+        Ok(HttpClient::new(
+            self.timeout,
+            self.connect_timeout,
+            self.retry.clone(),
+        )?)
+    }
+
+    /// Opens the history store, creating its directory if needed
+    pub fn open_history(&self) -> Result<Box<dyn HistoryStore>> {
+        if let Some(dir) = self.history_path().parent() {
+            fs::create_dir_all(dir)?;
+        }
+        history::open(self.history_path())
+    }
 }
 
-#[derive(Deserialize)]
-pub struct GroqResponse {
-    choices: Vec<Choice>,
+/// Model and system prompt for a session, command line values take precedence
+fn session_settings(config: &Config, session: &Session) -> (String, Option<String>) {
+    let model = config
+        .model
+        .clone()
+        .or(session.model.clone())
+        .unwrap_or_else(|| config.default_model.clone());
+    let system = config
+        .system
+        .clone()
+        .or(session.system.clone())
+        .or(config.default_system.clone());
+    (model, system)
 }
 
-#[derive(Deserialize)]
-pub struct Choice {
-    message: Message,
+/// Rough token count, about four characters per token for English text
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
 }
 
-#[derive(Deserialize)]
-pub struct Message {
-    content: String,
+/// Replays the last `context` entries as alternating user/assistant turns followed by
+/// the new prompt. With a token budget the oldest turns are dropped until the
+/// conversation fits, the new prompt is always kept.
+fn build_messages(
+    system: Option<&str>,
+    history: &[Entry],
+    context: usize,
+    max_tokens: Option<usize>,
+    prompt: &str,
+) -> Vec<ChatMessage> {
+    let mut turns = &history[history.len().saturating_sub(context)..];
+    if let Some(budget) = max_tokens {
+        let mut used = estimate_tokens(prompt) + system.map_or(0, estimate_tokens);
+        let kept = turns
+            .iter()
+            .rev()
+            .take_while(|entry| {
+                used += estimate_tokens(&entry.prompt) + estimate_tokens(&entry.response);
+                used <= budget
+            })
+            .count();
+        turns = &turns[turns.len() - kept..];
+    }
+
+    let mut messages = Vec::with_capacity(turns.len() * 2 + 2);
+    if let Some(system) = system {
+        messages.push(ChatMessage::new("system", system));
+    }
+    for entry in turns {
+        messages.push(ChatMessage::new("user", entry.prompt.clone()));
+        messages.push(ChatMessage::new("assistant", entry.response.clone()));
+    }
+    messages.push(ChatMessage::new("user", prompt));
+    messages
 }
 
-pub struct Config<'a> {
-    pub model: String,
-    pub arguments: &'a Vec<String>,
-    pub history_filepath: String,
-    pub context: usize,
-    pub dont_save: bool,
+pub fn show_history(config: &Config, count: usize) -> Result<()> {
+    let store = config.open_history()?;
+    let name = store.resolve_name(config.session.as_deref())?;
+
+    let chatlog = match store.session(&name)? {
+        Some(session) if !session.chatlog.is_empty() => session.chatlog,
+        _ => {
+            eprintln!("Chat log is empty");
+            return Ok(());
+        }
+    };
+
+    let output = chatlog
+        .iter()
+        .rev()
+        .take(count)
+        .fold(String::new(), |mut out, b| {
+            let _ = writeln!(out, "_Prompt_: {}", b.prompt);
+            for call in b.tool_log.iter().flat_map(|m| &m.tool_calls) {
+                let _ = writeln!(
+                    out,
+                    "_Tool_: {} {}",
+                    call.function.name, call.function.arguments
+                );
+            }
+            let cached = if b.cached { " (cached)" } else { "" };
+            let _ = writeln!(out, "_Response_{cached}: {}", b.response);
+            out
+        });
+    println!("{}", output);
+    Ok(())
 }
 
-#[derive(Deserialize, Serialize)]
-struct Entry {
-    prompt: String,
-    response: String,
+pub fn search_history(config: &Config, terms: &str, count: usize) -> Result<()> {
+    let store = config.open_history()?;
+    let hits = store.search(terms, count)?;
+    if hits.is_empty() {
+        eprintln!("No matching entries");
+        return Ok(());
+    }
+
+    let output = hits.iter().fold(String::new(), |mut out, hit| {
+        let time = hit
+            .entry
+            .timestamp
+            .and_then(|ts| DateTime::from_timestamp(ts, 0))
+            .map(|time| {
+                time.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_else(|| "-".to_string());
+        let _ = write!(
+            out,
+            "[{} | {}]\n_Prompt_: {}\n_Response_: {}\n\n",
+            hit.session, time, hit.entry.prompt, hit.entry.response
+        );
+        out
+    });
+    print!("{}", output);
+    Ok(())
 }
 
-#[derive(Deserialize, Serialize)]
-struct JsonData {
-    chatlog: Vec<Entry>,
+pub fn list_sessions(config: &Config) -> Result<()> {
+    let store = config.open_history()?;
+    let sessions = store.list()?;
+    if sessions.is_empty() {
+        eprintln!("No sessions yet");
+        return Ok(());
+    }
+    let current = store.resolve_name(None)?;
+    for session in sessions {
+        let marker = if session.name == current { "*" } else { " " };
+        let model = session.model.as_deref().unwrap_or("-");
+        println!(
+            "{marker} {}  ({} entries, model: {model})",
+            session.name, session.entries
+        );
+    }
+    Ok(())
 }
 
-fn save_to_file(res: Entry, config: &Config) -> Result<()> {
-    let history_path = Path::new(&config.history_filepath);
+pub fn show_usage(config: &Config, by: usage::GroupBy, since: Option<NaiveDate>) -> Result<()> {
+    let store = config.open_history()?;
+    let groups = usage::totals(store.as_ref(), &config.prices, by, since)?;
+    if groups.is_empty() {
+        eprintln!("No usage recorded");
+        return Ok(());
+    }
 
-    if !history_path.exists() {
-        let initial_data = JsonData { chatlog: vec![] };
-        let file = File::create(history_path)?;
-        let writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(writer, &initial_data)?;
+    let mut total = usage::Totals::default();
+    println!(
+        "{:<24} {:>8} {:>8} {:>14} {:>14} {:>10}",
+        "", "requests", "cached", "prompt tokens", "output tokens", "cost"
+    );
+    for (key, totals) in &groups {
+        print_usage_row(key, totals);
+        total.merge(totals);
+    }
+    print_usage_row("total", &total);
+    if total.unpriced > 0 {
+        eprintln!(
+            "* {} requests to models without a price in the config file are not in the cost",
+            total.unpriced
+        );
     }
+    Ok(())
+}
 
-    let file = File::open(history_path)?;
-    let reader = BufReader::new(&file);
-    let mut data: JsonData = match serde_json::from_reader(reader) {
-        Ok(data) => data,
-        Err(_) => JsonData { chatlog: vec![] },
-    };
-    data.chatlog.push(res);
+fn print_usage_row(key: &str, totals: &usage::Totals) {
+    let cost = format!(
+        "${:.4}{}",
+        totals.cost,
+        if totals.unpriced > 0 { "*" } else { "" }
+    );
+    println!(
+        "{key:<24} {:>8} {:>8} {:>14} {:>14} {cost:>10}",
+        totals.requests, totals.cached, totals.prompt_tokens, totals.completion_tokens
+    );
+}
 
-    let file = File::create(history_path)?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, &data)?;
+/// Lists the templates in the directory with the placeholders they use
+pub fn list_templates(dir: &Path) -> Result<()> {
+    let templates = template::list(dir)?;
+    if templates.is_empty() {
+        eprintln!("No templates in {}", dir.display());
+        return Ok(());
+    }
+    for template in templates {
+        let placeholders: Vec<String> = template
+            .placeholders()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        println!("{:<20} {}", template.name, placeholders.join(" "));
+    }
+    Ok(())
+}
 
+/// Stores the provider's api key in the key store, encrypted with its passphrase,
+/// or the command that prints it
+pub fn login(keys_path: &Path, kind: ProviderKind, command: Option<&str>) -> Result<()> {
+    let name = kind.name();
+    if kind.api_key_env().is_none() {
+        bail!("{name} does not use an api key");
+    }
+    let mut store = KeyStore::open(keys_path)?;
+    match command {
+        Some(command) => {
+            store.set_command(name, command)?;
+            eprintln!("Stored the {name} key command in {}", keys_path.display());
+        }
+        None => {
+            let key = keys::read_key(name)?;
+            let passphrase = if store.has_encrypted() {
+                keys::passphrase("Passphrase of the api key store: ")?
+            } else {
+                keys::new_passphrase()?
+            };
+            store.set_key(name, &key, &passphrase)?;
+            eprintln!("Stored the {name} api key in {}", keys_path.display());
+        }
+    }
     Ok(())
 }
 
-fn get_history(config: &Config) -> Result<JsonData> {
-    let file = File::open(&config.history_filepath)
-        .with_context(|| "Failed to open config file".to_string())?;
-    let reader = BufReader::new(&file);
-    let data: JsonData =
-        serde_json::from_reader(reader).with_context(|| "Couldn't Deserialize data".to_string())?;
-    Ok(data)
+/// Removes the provider's api key from the key store
+pub fn logout(keys_path: &Path, kind: ProviderKind) -> Result<()> {
+    let name = kind.name();
+    if KeyStore::open(keys_path)?.remove(name)? {
+        eprintln!("Removed the {name} api key");
+    } else {
+        eprintln!("No {name} api key is stored");
+    }
+    Ok(())
 }
 
-pub fn show_history(config: &Config, count: usize) -> Result<()> {
-    let mut data = get_history(config).with_context(|| {
-        "Couldnt retrieve history from the config file for some reason".to_string()
-    })?;
+/// Removes the entries the retention policy does not keep from every session
+pub fn prune_history(config: &Config, retention: &history::Retention) -> Result<()> {
+    if retention.is_unlimited() {
+        bail!(
+            "No retention policy, set max_entries or max_age_days in the [history] table \
+             of the config file or pass --max-entries or --max-age-days"
+        );
+    }
+    let pruned = config.open_history()?.prune(retention)?;
+    println!("Pruned {pruned} entries");
+    Ok(())
+}
+
+/// Chunks the text and Markdown files in the directory and saves their index
+pub fn index_directory(dir: &Path) -> Result<()> {
+    let index = Index::build(dir)?;
+    let path = index.save()?;
+    eprintln!(
+        "Indexed {} chunks from {} files into {}",
+        index.chunks.len(),
+        index.files,
+        path.display()
+    );
+    Ok(())
+}
 
-    if data.chatlog.is_empty() {
-        eprintln!("Chat log is empty");
+/// Copies the sessions of a history file, JSONL export or ChatGPT export into the
+/// configured history store
+pub fn import_history(config: &Config, from: &Path, format: Option<ImportFormat>) -> Result<()> {
+    let mut store = config.open_history()?;
+    archive::import(store.as_mut(), &config.history_policy, from, format)
+}
+
+/// Writes the session, or every session with `all`, to the file or stdout
+pub fn export_history(
+    config: &Config,
+    all: bool,
+    range: DateRange,
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> Result<()> {
+    let store = config.open_history()?;
+    let names = if all {
+        store.list()?.into_iter().map(|s| s.name).collect()
+    } else {
+        vec![store.resolve_name(config.session.as_deref())?]
+    };
+    let exported = archive::export(store.as_ref(), &names, range, format)?;
+    if exported.is_empty() {
+        eprintln!("Nothing to export");
         return Ok(());
     }
-
-    let output = data
-        .chatlog
-        .iter_mut()
-        .rev()
-        .take(count)
-        .fold(String::new(), |mut out, b| {
-            let _ = write!(
-                out,
-                "_Prompt_: {}\n_Response_: {}\n",
-                b.prompt, b.response
-            );
-            out
-        });
-    println!("{}", output);
+    match output {
+        Some(path) => fs::write(path, exported)
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        None => print!("{exported}"),
+    }
     Ok(())
 }
 
-pub async fn run(config: &Config<'_>, api_key: &String) -> Result<()> {
-    if api_key.is_empty() {
-        eprintln!("Error: Api key is not set properly");
-        process::exit(1)
+/// A finished reply to a prompt
+#[derive(Default)]
+struct Answer {
+    text: String,
+    /// Tool calls and results exchanged before the reply
+    tool_log: Vec<ChatMessage>,
+    /// Tokens used by every request made for the reply
+    usage: Option<Usage>,
+    /// The stream was cut off with Ctrl-C, the text is incomplete
+    interrupted: bool,
+    /// The reply was reused from the cache
+    cached: bool,
+}
+
+impl Answer {
+    fn into_entry(self, prompt: String, model: &str) -> Entry {
+        let mut entry = Entry::new(prompt, self.text, model).with_usage(self.usage);
+        entry.tool_log = self.tool_log;
+        entry.cached = self.cached;
+        entry
     }
+}
 
-    let client = Client::new();
-    let mut content_str = String::new();
+/// Prints the tokens of a streamed response as they arrive, adding them to the
+/// answer so what was received is kept when the request is cancelled
+async fn stream_response(
+    response: Response,
+    provider: &dyn Provider,
+    out: &mut Output,
+    answer: &mut Answer,
+) -> Result<()> {
+    let mut body = response.bytes_stream();
+    let mut decoder = stream::EventDecoder::new(provider.stream_format());
 
-    match get_history(config) {
-        Ok(history) => {
-            for entry in history.chatlog.iter().rev().take(config.context) {
-                content_str.push_str(&entry.prompt);
-                content_str.push('\n');
-                content_str.push_str(&entry.response);
-                content_str.push('\n');
+    loop {
+        let (payloads, done) = match body.next().await {
+            Some(chunk) => (decoder.push(&chunk.map_err(ApiError::from)?), false),
+            None => (decoder.finish().into_iter().collect(), true),
+        };
+        for data in payloads {
+            let delta = provider
+                .parse_stream_event(&data)
+                .map_err(|err| ApiError::Malformed(err.to_string()))?;
+            if let Some(delta) = delta.text {
+                out.write(&delta);
+                answer.text.push_str(&delta);
             }
+            answer.usage = Usage::combine(answer.usage, delta.usage);
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends the request without streaming and returns the reply
+pub(crate) async fn request_reply(
+    http: &HttpClient,
+    provider: &dyn Provider,
+    req: &ChatRequest<'_>,
+) -> Result<Reply> {
+    let response = http.send(provider.build_request(&http.client, req)).await?;
+    let body = api::read_json(response).await?;
+    Ok(provider
+        .parse_response(body)
+        .map_err(|err| ApiError::Malformed(err.to_string()))?)
+}
+
+/// Sends the request and prints the reply into the answer, token by token when
+/// streaming
+async fn complete(
+    http: &HttpClient,
+    provider: &dyn Provider,
+    req: &ChatRequest<'_>,
+    out: &mut Output,
+    answer: &mut Answer,
+) -> Result<()> {
+    if req.stream {
+        let response = http.send(provider.build_request(&http.client, req)).await?;
+        stream_response(response, provider, out, answer).await
+    } else {
+        let reply = request_reply(http, provider, req).await?;
+        out.write(&reply.message.content);
+        answer.text = reply.message.content;
+        answer.usage = reply.usage;
+        Ok(())
+    }
+}
+
+/// Asks for a reply matching the schema, sending the problems back to the model when
+/// it does not. Prints only the validated JSON and returns it.
+async fn complete_structured(
+    http: &HttpClient,
+    provider: &dyn Provider,
+    schema: &JsonSchema,
+    req: &ChatRequest<'_>,
+) -> Result<Answer> {
+    let mut messages = req.messages.to_vec();
+    match messages.first_mut() {
+        Some(system) if system.role == "system" => {
+            system.content = format!("{}\n\n{}", system.content, schema.instruction())
         }
-        Err(err) => {
-            if config.context > 0 {
-                eprintln!("Error: {err}");
-                process::exit(1)
+        _ => messages.insert(0, ChatMessage::new("system", schema.instruction())),
+    }
+    let mut usage = None;
+
+    for attempt in 1..=SCHEMA_ATTEMPTS {
+        let round = ChatRequest {
+            messages: &messages,
+            stream: false,
+            json_schema: Some(&schema.schema),
+            ..*req
+        };
+        let reply = request_reply(http, provider, &round).await?;
+        usage = Usage::combine(usage, reply.usage);
+        let text = reply.message.content;
+        match schema.validate(&text) {
+            Ok(value) => {
+                let json = serde_json::to_string_pretty(&value)?;
+                println!("{json}");
+                return Ok(Answer {
+                    text: json,
+                    usage,
+                    ..Answer::default()
+                });
+            }
+            Err(problems) => {
+                eprintln!(
+                    "Reply does not match the schema ({attempt}/{SCHEMA_ATTEMPTS}): {problems}"
+                );
+                messages.push(ChatMessage::new("assistant", text));
+                messages.push(ChatMessage::new(
+                    "user",
+                    format!(
+                        "Your reply does not match the JSON schema: {problems}\n\
+                         Reply again with only the corrected JSON."
+                    ),
+                ));
             }
         }
     }
-    content_str.push_str(&config.arguments.join(" "));
+    bail!("No reply matched the JSON schema after {SCHEMA_ATTEMPTS} attempts")
+}
+
+/// Sends the conversation without streaming and runs the tools the model asks for,
+/// feeding their output back until it gives a final answer. The answer keeps the
+/// tool calls and results exchanged on the way.
+async fn complete_with_tools(
+    http: &HttpClient,
+    provider: &dyn Provider,
+    tools: &ToolsConfig,
+    req: &ChatRequest<'_>,
+    confirm: &mut dyn FnMut(&FunctionCall) -> bool,
+    out: &mut Output,
+) -> Result<Answer> {
+    if !provider.supports_tools() {
+        bail!("Tools are only supported by OpenAI-compatible providers");
+    }
+    let definitions = tools.definitions()?;
+    let mut messages = req.messages.to_vec();
+    let mut tool_log = vec![];
+    let mut usage = None;
+
+    for _ in 0..tools.max_rounds {
+        let round = ChatRequest {
+            messages: &messages,
+            stream: false,
+            tools: &definitions,
+            ..*req
+        };
+        let reply = request_reply(http, provider, &round).await?;
+        usage = Usage::combine(usage, reply.usage);
+        let message = reply.message;
+        if message.tool_calls.is_empty() {
+            out.write(&message.content);
+            return Ok(Answer {
+                text: message.content,
+                tool_log,
+                usage,
+                ..Answer::default()
+            });
+        }
+
+        let calls = message.tool_calls.clone();
+        messages.push(message.clone());
+        tool_log.push(message);
+        for call in &calls {
+            let output = if confirm(&call.function) {
+                tools
+                    .execute(&call.function)
+                    .unwrap_or_else(|err| format!("Error: {err:#}"))
+            } else {
+                "The user declined to run this tool".to_string()
+            };
+            let result = ChatMessage::tool_result(&call.id, output);
+            messages.push(result.clone());
+            tool_log.push(result);
+        }
+    }
+    bail!(
+        "No final answer after {} rounds of tool calls",
+        tools.max_rounds
+    )
+}
+
+/// The tools offered to the model. They need a provider that can call them and a
+/// terminal to confirm the calls on, without either the model answers on its own.
+fn offered_tools<'c>(config: &'c Config, provider: &dyn Provider) -> Option<&'c ToolsConfig> {
+    if config.tools.is_empty() || !provider.supports_tools() {
+        return None;
+    }
+    if !tools::can_confirm() {
+        eprintln!("Not offering tools without a terminal to confirm them");
+        return None;
+    }
+    Some(&config.tools)
+}
+
+/// Prints the reply to the request, as JSON when a schema is given and otherwise
+/// letting the model call tools when any are offered
+async fn respond(
+    config: &Config<'_>,
+    http: &HttpClient,
+    provider: &dyn Provider,
+    req: &ChatRequest<'_>,
+) -> Result<Answer> {
+    let tools = offered_tools(config, provider);
+    // Replies that depend on tool output are not cached, the output may change
+    let cache = config.cache.as_ref().filter(|_| tools.is_none());
+    let key = cache.map(|_| {
+        let schema = config.json_schema.as_ref().map(|schema| &schema.schema);
+        ResponseCache::key(
+            provider.base_url(),
+            &ChatRequest {
+                json_schema: schema,
+                ..*req
+            },
+        )
+    });
+    let mut out = Output::new(config.markdown);
+    if let Some(reply) = cache.zip(key.as_ref()).and_then(|(c, key)| c.get(key)) {
+        let text = reply.message.content;
+        if config.json_schema.is_some() {
+            println!("{text}");
+        } else {
+            out.write(&text);
+            out.finish();
+            println!("\n");
+        }
+        // Nothing was billed for a cached reply
+        return Ok(Answer {
+            text,
+            usage: Some(Usage::default()),
+            cached: true,
+            ..Answer::default()
+        });
+    }
 
-    let req = GroqRequest {
-        model: config.model.clone(),
-        messages: vec![GroqMessage {
-            role: "user".to_string(),
-            content: content_str.clone(),
-        }],
+    // A single Ctrl-C handler covers the whole request, streamed or not. What was
+    // streamed before it is kept, otherwise the request fails.
+    let mut answer = Answer::default();
+    let request = async {
+        if let Some(schema) = &config.json_schema {
+            answer = complete_structured(http, provider, schema, req).await?;
+        } else if let Some(tools) = tools {
+            let mut confirm = tools::confirm_on_terminal;
+            answer =
+                complete_with_tools(http, provider, tools, req, &mut confirm, &mut out).await?;
+        } else {
+            complete(http, provider, req, &mut out, &mut answer).await?;
+        }
+        anyhow::Ok(())
+    };
+    answer.interrupted = tokio::select! {
+        result = request => {
+            result?;
+            false
+        }
+        _ = tokio::signal::ctrl_c() => {
+            eprint!("\n[interrupted]");
+            true
+        }
     };
+    if answer.interrupted && answer.text.is_empty() {
+        println!();
+        bail!("Interrupted");
+    }
+    if config.json_schema.is_none() {
+        out.finish();
+        println!("\n");
+    }
+    if let Some((cache, key)) = cache.zip(key).filter(|_| !answer.interrupted) {
+        let reply = Reply {
+            message: ChatMessage::new("assistant", answer.text.clone()),
+            usage: answer.usage,
+        };
+        if let Err(err) = cache.put(&key, &reply) {
+            eprintln!("Failed to cache the reply: {err}");
+        }
+    }
+    Ok(answer)
+}
+
+/// The prompt preceded by the chunks retrieved for it with `--rag`, their sources
+/// are listed on stderr. Only the prompt itself is saved to the history.
+pub(crate) fn with_sources(config: &Config, prompt: &str) -> String {
+    let Some(index) = &config.rag else {
+        return prompt.to_string();
+    };
+    let hits = index.search(prompt, config.rag_top);
+    if hits.is_empty() {
+        eprintln!("No sources matched the prompt");
+    }
+    for (i, hit) in hits.iter().enumerate() {
+        eprintln!("[{}] {}", i + 1, index.citation(hit.chunk));
+    }
+    index.with_context(prompt, &hits)
+}
+
+pub async fn run(config: &Config<'_>, provider: &dyn Provider) -> Result<()> {
+    let http = config.http_client()?;
+    let mut prompt = input::compose(&config.arguments.join(" "), &config.attachments);
+    if let Some(template) = &config.template {
+        prompt = template.render(&prompt, &config.template_vars)?;
+    }
 
-    let response = client
-        .post(API)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&req)
-        .send()
-        .await?;
-
-    let response_text: GroqResponse = response.json().await?;
-    let llm_response = &response_text.choices[0].message.content;
-    println!("_Response_: {}\n", llm_response);
-
-    let entry = Entry {
-        prompt: content_str,
-        response: llm_response.to_string(),
+    let mut store = config.open_history()?;
+    let name = store.resolve_name(config.session.as_deref())?;
+    let session = store.session(&name)?.unwrap_or_default();
+    let (model, system) = session_settings(config, &session);
+
+    let messages = build_messages(
+        system.as_deref(),
+        &session.chatlog,
+        config.context,
+        config.max_context_tokens,
+        &with_sources(config, &prompt),
+    );
+    let req = ChatRequest {
+        model: &model,
+        messages: &messages,
+        stream: config.stream,
+        temperature: config.temperature,
+        max_tokens: config.max_tokens,
+        tools: &[],
+        json_schema: None,
     };
+
+    // Structured output is meant for scripts, so only the JSON goes to stdout
+    if config.json_schema.is_none() {
+        print!("_Response_: ");
+    }
+    let answer = respond(config, &http, provider, &req).await?;
+
     if !config.dont_save {
-        save_to_file(entry, config)?;
+        let entry = answer.into_entry(prompt, &model);
+        config.history_policy.save(store.as_mut(), &name, &entry)?;
     }
     Ok(())
 }
+
+/// Runs the OpenAI-compatible server until Ctrl-C, logging every exchange into the
+/// session given with `--session` or the `serve` session
+pub async fn serve(
+    config: &Config<'_>,
+    provider: Box<dyn Provider>,
+    addr: SocketAddr,
+) -> Result<()> {
+    let history = if config.dont_save {
+        None
+    } else {
+        config.open_history()?;
+        Some(PathBuf::from(&config.history_filepath))
+    };
+    let server = serve::Server {
+        provider,
+        http: config.http_client()?,
+        default_model: config
+            .model
+            .clone()
+            .unwrap_or_else(|| config.default_model.clone()),
+        history,
+        history_policy: config.history_policy.clone(),
+        session: config
+            .session
+            .clone()
+            .unwrap_or_else(|| serve::DEFAULT_SESSION.to_string()),
+        cache: config.cache.clone(),
+    };
+    serve::run(server, addr).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(prompt: &str, response: &str) -> Entry {
+        Entry::new(prompt.to_string(), response.to_string(), "test-model")
+    }
+
+    fn config(arguments: &Vec<String>) -> Config<'_> {
+        Config {
+            model: None,
+            default_model: "test-model".to_string(),
+            arguments,
+            attachments: vec![],
+            template: None,
+            template_vars: BTreeMap::new(),
+            rag: None,
+            rag_top: rag::DEFAULT_TOP,
+            history_filepath: String::new(),
+            history_policy: history::Policy::default(),
+            session: None,
+            system: None,
+            default_system: None,
+            temperature: None,
+            max_tokens: None,
+            context: 0,
+            max_context_tokens: None,
+            dont_save: true,
+            stream: false,
+            markdown: false,
+            tools: ToolsConfig::default(),
+            json_schema: None,
+            prices: BTreeMap::new(),
+            cache: None,
+            timeout: None,
+            connect_timeout: Duration::from_secs(5),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    #[test]
+    fn context_is_replayed_as_turns() {
+        let history = vec![entry("one", "1"), entry("two", "2"), entry("three", "3")];
+        let messages = build_messages(None, &history, 2, None, "four");
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "user", "assistant", "user"]);
+        assert_eq!(contents, ["two", "2", "three", "3", "four"]);
+    }
+
+    #[test]
+    fn token_budget_drops_oldest_turns() {
+        let history = vec![entry(&"a".repeat(40), "b"), entry("two", "2")];
+        let messages = build_messages(None, &history, 10, Some(10), "three");
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["two", "2", "three"]);
+
+        let messages = build_messages(Some("sys"), &history, 10, Some(0), "three");
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user"]);
+    }
+
+    #[tokio::test]
+    async fn tool_calls_are_run_until_an_answer() {
+        let mut server = mockito::Server::new_async().await;
+        // Mocks are tried in creation order, the tool result only shows up in the second request
+        let answer = server
+            .mock("POST", "/chat/completions")
+            .match_body(mockito::Matcher::Regex(
+                r#""content":"[^"]*lib.rs[^"]*","role":"tool","tool_call_id":"call_1""#.to_string(),
+            ))
+            .with_body(
+                r#"{"choices":[{"message":{"role":"assistant","content":"done"}}],
+                    "usage":{"prompt_tokens":30,"completion_tokens":2}}"#,
+            )
+            .create_async()
+            .await;
+
+        let call = server
+            .mock("POST", "/chat/completions")
+            .with_body(
+                r#"{"choices":[{"message":{"role":"assistant","content":null,"tool_calls":[
+                    {"id":"call_1","type":"function",
+                     "function":{"name":"list_dir","arguments":"{\"path\":\"src\"}"}}]}}],
+                    "usage":{"prompt_tokens":10,"completion_tokens":5}}"#,
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let provider =
+            provider::build_provider(provider::ProviderKind::Openai, Some(server.url()), None);
+        let http = HttpClient::new(None, Duration::from_secs(5), RetryPolicy::default()).unwrap();
+        let tools = ToolsConfig {
+            enabled: vec!["list_dir".to_string()],
+            ..Default::default()
+        };
+        let messages = vec![ChatMessage::new("user", "what is in src?")];
+        let req = ChatRequest {
+            model: "test-model",
+            messages: &messages,
+            stream: true,
+            temperature: None,
+            max_tokens: None,
+            tools: &[],
+            json_schema: None,
+        };
+        let mut asked = vec![];
+        let mut confirm = |call: &FunctionCall| {
+            asked.push(call.name.clone());
+            true
+        };
+
+        let reply = complete_with_tools(
+            &http,
+            provider.as_ref(),
+            &tools,
+            &req,
+            &mut confirm,
+            &mut Output::Plain,
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.text, "done");
+        assert_eq!(asked, ["list_dir"]);
+        let roles: Vec<&str> = reply.tool_log.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["assistant", "tool"]);
+        let usage = reply.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (40, 7));
+        call.assert_async().await;
+        answer.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn tools_are_not_offered_without_provider_support() {
+        let mut server = mockito::Server::new_async().await;
+        let plain = server
+            .mock("POST", "/messages")
+            .with_body(r#"{"content":[{"type":"text","text":"hello"}]}"#)
+            .create_async()
+            .await;
+        let provider = provider::build_provider(
+            provider::ProviderKind::Anthropic,
+            Some(server.url()),
+            Some("key".to_string()),
+        );
+        let arguments = vec![];
+        let mut config = config(&arguments);
+        config.tools = ToolsConfig {
+            enabled: vec!["list_dir".to_string()],
+            ..Default::default()
+        };
+        let http = config.http_client().unwrap();
+        let messages = vec![ChatMessage::new("user", "what is in src?")];
+        let req = ChatRequest {
+            model: "test-model",
+            messages: &messages,
+            stream: false,
+            temperature: None,
+            max_tokens: None,
+            tools: &[],
+            json_schema: None,
+        };
+
+        let answer = respond(&config, &http, provider.as_ref(), &req)
+            .await
+            .unwrap();
+        assert_eq!(answer.text, "hello");
+        assert!(answer.tool_log.is_empty());
+        plain.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn schema_mismatch_is_sent_back() {
+        let mut server = mockito::Server::new_async().await;
+        // Mocks are tried in creation order, the validation error only shows up in the retry
+        let fixed = server
+            .mock("POST", "/chat/completions")
+            .match_body(mockito::Matcher::Regex(
+                "does not match the JSON schema: .*required property".to_string(),
+            ))
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"{\"n\": 2}"}}]}"#)
+            .create_async()
+            .await;
+        let wrong = server
+            .mock("POST", "/chat/completions")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "response_format": {"type": "json_schema"},
+            })))
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"{}"}}]}"#)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let provider =
+            provider::build_provider(provider::ProviderKind::Openai, Some(server.url()), None);
+        let http = HttpClient::new(None, Duration::from_secs(5), RetryPolicy::default()).unwrap();
+        let schema = JsonSchema::new(serde_json::json!({
+            "type": "object",
+            "properties": {"n": {"type": "integer"}},
+            "required": ["n"],
+        }))
+        .unwrap();
+        let messages = vec![ChatMessage::new("user", "pick a number")];
+        let req = ChatRequest {
+            model: "test-model",
+            messages: &messages,
+            stream: true,
+            temperature: None,
+            max_tokens: None,
+            tools: &[],
+            json_schema: None,
+        };
+
+        let answer = complete_structured(&http, provider.as_ref(), &schema, &req)
+            .await
+            .unwrap();
+        assert_eq!(answer.text, "{\n  \"n\": 2\n}");
+        wrong.assert_async().await;
+        fixed.assert_async().await;
+    }
+}
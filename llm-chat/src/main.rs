@@ -1,23 +1,71 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{env, process};
 
 use anyhow::Result;
+use chrono::NaiveDate;
 use clap::CommandFactory;
 use clap::{Parser, Subcommand};
 use clap_complete::Shell;
+use llm_chat::api::RetryPolicy;
+use llm_chat::archive::{DateRange, ExportFormat, ImportFormat};
+use llm_chat::keys::{self, KeyStore};
+use llm_chat::provider::{self, Provider, ProviderKind};
+use llm_chat::schema::JsonSchema;
+use llm_chat::usage::GroupBy;
+use llm_chat::{config_file, input, rag, template};
+
+const DEFAULT_CHAT_CONTEXT: usize = 20;
+const DEFAULT_HISTORY_FILE: &str = "/.local/share/llm_chat/history.db";
+const LEGACY_HISTORY_FILE: &str = "/.local/share/llm.json";
 
 /// Simple program to interact with LLM in terminal
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Name of the model
-    #[arg(short, long, default_value_t = String::from("llama-3.3-70b-versatile"), long_help = "Possible values are:\ndistil-whisper-large-v3-en\ngemma2-9b-it\nllama-3.3-70b-versatile\nllama-3.1-8b-instant\nllama-guard-3-8b\nllama3-70b-8192\nllama3-8b-8192\nmixtral-8x7b-32768")]
-    model: String,
+    /// Name of the model, defaults to the profile's or the provider's default model
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// Profile from the config file to use
+    #[arg(short = 'P', long)]
+    profile: Option<String>,
+
+    /// Sampling temperature
+    #[arg(long)]
+    temperature: Option<f64>,
+
+    /// Maximum number of tokens in the response
+    #[arg(long)]
+    max_tokens: Option<u32>,
+
+    /// Backend to send the prompt to [default: groq]
+    #[arg(short, long, value_enum)]
+    provider: Option<ProviderKind>,
 
-    /// Filepath where history should be saved
+    /// Base url of the provider's api, for self hosted OpenAI-compatible servers
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// Path of the config file [default: ~/.config/llm_chat/config.toml]
+    #[arg(long)]
+    config: Option<String>,
+
+    /// File to attach to the prompt, can be given multiple times
+    #[arg(short, long = "file")]
+    files: Vec<String>,
+
+    /// Largest total size of piped stdin and attached files
+    #[arg(long, default_value_t = 256 * 1024)]
+    max_input_bytes: usize,
+
+    /// Filepath where history should be saved, a .json file keeps the old JSON format
     #[arg(long)]
     history_filepath: Option<String>,
 
-    /// Groq Api key
+    /// Api key for the provider, other users can see it in `ps`, prefer the
+    /// environment or `llm_chat login`
     #[arg(short, long)]
     api_key: Option<String>,
 
@@ -30,9 +78,78 @@ struct Args {
     )]
     dont_save_history: bool,
 
-    /// No. of previous prompts and responses to be used as context
-    #[arg(short, long, default_value_t = 0)]
-    context: usize,
+    /// Wait for the whole response instead of printing tokens as they arrive
+    #[arg(long, default_value_t = false)]
+    no_stream: bool,
+
+    /// Reuse the reply to an identical earlier request instead of asking the api again
+    #[arg(long, overrides_with = "no_cache")]
+    cache: bool,
+
+    /// Always ask the api, overrides `enabled = true` in the `[cache]` config
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Seconds a cached reply is reused for, overrides the config file
+    #[arg(long, value_name = "SECONDS")]
+    cache_ttl: Option<u64>,
+
+    /// Render Markdown in responses when printing to a terminal
+    #[arg(long, overrides_with = "no_markdown")]
+    markdown: bool,
+
+    /// Print responses as plain text, overrides `markdown = true` in the config file
+    #[arg(long)]
+    no_markdown: bool,
+
+    /// Seconds to wait for data from the api before giving up, 0 waits forever
+    #[arg(long, default_value_t = 120)]
+    timeout: u64,
+
+    /// Seconds to wait for the connection to the api
+    #[arg(long, default_value_t = 10)]
+    connect_timeout: u64,
+
+    /// How often to retry rate limited, failed or unreachable requests
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+    /// No. of previous prompts and responses to be used as context [default: 0, 20 in chat]
+    #[arg(short, long)]
+    context: Option<usize>,
+
+    /// Drop the oldest context turns until the conversation fits in this many tokens
+    #[arg(long)]
+    max_context_tokens: Option<usize>,
+
+    /// Named session to use, defaults to the resumed session or "default"
+    #[arg(short, long)]
+    session: Option<String>,
+
+    /// System prompt for this request, overrides the session's system prompt
+    #[arg(long)]
+    system: Option<String>,
+
+    /// Template from the templates directory to fill the prompt into
+    #[arg(short, long)]
+    template: Option<String>,
+
+    /// Value for a `{{name}}` placeholder of the template, as name=value
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = template::parse_var)]
+    vars: Vec<(String, String)>,
+
+    /// Directory indexed with `llm_chat index`, the chunks that best match each
+    /// prompt are sent along with it and cited in the reply
+    #[arg(long, value_name = "DIR")]
+    rag: Option<PathBuf>,
+
+    /// Number of chunks sent along with each prompt with --rag
+    #[arg(long, value_name = "N", default_value_t = rag::DEFAULT_TOP)]
+    rag_top: usize,
+
+    /// Ask for JSON matching the schema in this file and print only the validated JSON
+    #[arg(long, value_name = "FILE")]
+    json_schema: Option<PathBuf>,
 
     /// Subcommands
     #[command(subcommand)]
@@ -51,6 +168,115 @@ enum Commands {
         count: usize,
     },
 
+    /// Full-text search over the prompts and responses of every session
+    SearchHistory {
+        /// Words that must all appear in the entry
+        #[arg(required = true)]
+        terms: Vec<String>,
+
+        /// Maximum number of entries to display
+        #[arg(short, long, default_value_t = 10)]
+        count: usize,
+    },
+
+    /// Import sessions from a JSON history file, a JSONL export or a ChatGPT export
+    ImportHistory {
+        /// Path of the file to import
+        from: String,
+
+        /// Format of the file, detected from its content when not given
+        #[arg(short, long, value_enum)]
+        format: Option<ImportFormat>,
+    },
+
+    /// Export the session, or every session, to Markdown, JSONL or HTML
+    ExportHistory {
+        /// Output format, defaults to the output file's extension or Markdown
+        #[arg(short, long, value_enum)]
+        format: Option<ExportFormat>,
+
+        /// Export every session instead of the one in use
+        #[arg(long)]
+        all: bool,
+
+        /// Only export entries from this day on, as YYYY-MM-DD
+        #[arg(long)]
+        since: Option<NaiveDate>,
+
+        /// Only export entries up to and including this day, as YYYY-MM-DD
+        #[arg(long)]
+        until: Option<NaiveDate>,
+
+        /// File to write to instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Remove old entries from every session, by default with the retention policy
+    /// of the config file's [history] table
+    Prune {
+        /// Entries kept per session, the oldest are removed first
+        #[arg(long)]
+        max_entries: Option<usize>,
+
+        /// Remove entries older than this many days
+        #[arg(long)]
+        max_age_days: Option<u64>,
+    },
+
+    /// Start an interactive chat on the session
+    Chat,
+
+    /// List the prompt templates and their placeholders
+    Templates,
+
+    /// Build the keyword index of the text and Markdown files in a directory for
+    /// --rag, run it again after the files change
+    Index { dir: PathBuf },
+
+    /// Store an api key for the provider, encrypted with a passphrase that is
+    /// read from LLM_CHAT_PASSPHRASE or asked for
+    Login {
+        #[arg(value_enum)]
+        provider: ProviderKind,
+
+        /// Command that prints the key, like `pass show groq`, run each time
+        /// the key is needed instead of storing the key
+        #[arg(long)]
+        command: Option<String>,
+    },
+
+    /// Remove the stored api key of the provider
+    Logout {
+        #[arg(value_enum)]
+        provider: ProviderKind,
+    },
+
+    /// Serve an OpenAI-compatible api that forwards to the provider and logs every
+    /// exchange into the history, with the last user message as the prompt
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+    },
+
+    /// Report token usage and cost, priced with the config file's price table
+    Usage {
+        /// How to group the totals
+        #[arg(short, long, value_enum, default_value_t = GroupBy::Day)]
+        by: GroupBy,
+
+        /// Only count entries from this day on, as YYYY-MM-DD
+        #[arg(long)]
+        since: Option<NaiveDate>,
+    },
+
+    /// Manage named sessions
+    Sessions {
+        #[command(subcommand)]
+        action: SessionAction,
+    },
+
     /// Generate shell completion scripts
     #[clap(name = "completion")]
     Completion {
@@ -62,24 +288,208 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum SessionAction {
+    /// List all sessions, the one in use is marked with *
+    List,
+
+    /// Rename a session
+    Rename { from: String, to: String },
+
+    /// Delete a session and its history
+    Delete { name: String },
+
+    /// Copy a session and its history under a new name
+    Fork { from: String, to: String },
+
+    /// Use this session when --session is not given
+    Resume { name: String },
+
+    /// Set the model or system prompt of a session, an empty value unsets it
+    Set {
+        name: String,
+
+        #[arg(short, long)]
+        model: Option<String>,
+
+        #[arg(long)]
+        system: Option<String>,
+    },
+}
+
+/// Stored api key of the provider, exiting if the store cannot give it
+fn stored_key(kind: ProviderKind, keys_path: Option<&Path>) -> Option<String> {
+    let store = match KeyStore::open(keys_path?) {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            process::exit(1)
+        }
+    };
+    match store.get(kind.name(), || {
+        keys::passphrase("Passphrase of the api key store: ")
+    }) {
+        Ok(key) => key,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            process::exit(1)
+        }
+    }
+}
+
+/// Builds the provider, exiting if its api key is missing. The key comes from the
+/// -a flag, the environment or the key store, in that order.
+fn make_provider(
+    kind: ProviderKind,
+    base_url: Option<String>,
+    api_key: Option<String>,
+    api_key_env: Option<String>,
+    keys_path: Option<&Path>,
+) -> Box<dyn Provider> {
+    let api_key_env = api_key_env.as_deref().or(kind.api_key_env());
+    let api_key = match (api_key, api_key_env) {
+        (Some(value), _) => Some(value),
+        (None, Some(var)) => match env::var(var).ok().or_else(|| stored_key(kind, keys_path)) {
+            Some(value) => Some(value),
+            None => {
+                eprintln!(
+                    "Error: Please make sure your {var} is in the environment, store one with `llm_chat login {}` or provide one with the -a flag",
+                    kind.name()
+                );
+                process::exit(1)
+            }
+        },
+        (None, None) => None,
+    };
+    if api_key.as_ref().is_some_and(|key| key.is_empty()) {
+        eprintln!("Error: Api key is not set properly");
+        process::exit(1)
+    }
+    provider::build_provider(kind, base_url, api_key)
+}
+
+/// Imports the JSON history used by older versions the first time the default
+/// history database is created
+fn migrate_legacy_history(config: &llm_chat::Config) -> Result<()> {
+    let Ok(home) = env::var("HOME") else {
+        return Ok(());
+    };
+    let legacy = PathBuf::from(home + LEGACY_HISTORY_FILE);
+    if Path::new(&config.history_filepath).exists() || !legacy.exists() {
+        return Ok(());
+    }
+    llm_chat::import_history(config, &legacy, Some(ImportFormat::History))?;
+    eprintln!(
+        "Migrated history from {} to {}",
+        legacy.display(),
+        config.history_filepath
+    );
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Args::parse();
 
+    let config_path: Option<PathBuf> = cli
+        .config
+        .as_ref()
+        .map(Into::into)
+        .or_else(config_file::default_path);
+    let mut file_config = match &config_path {
+        Some(path) => config_file::load(path)?,
+        None => config_file::FileConfig::default(),
+    };
+    let templates_dir = config_path.as_deref().map(template::templates_dir);
+    let keys_path = config_path.as_deref().map(keys::store_path);
+    let template = match (&cli.template, &templates_dir) {
+        (Some(name), Some(dir)) => Some(template::Template::load(dir, name)?),
+        (Some(_), None) => {
+            eprintln!("Error: No templates directory, set HOME or pass --config");
+            process::exit(1)
+        }
+        (None, _) => None,
+    };
+    let tools = std::mem::take(&mut file_config.tools);
+    let prices = std::mem::take(&mut file_config.prices);
+    let mut cache = std::mem::take(&mut file_config.cache);
+    let mut history_config = std::mem::take(&mut file_config.history);
+    if let Some(Commands::Prune {
+        max_entries,
+        max_age_days,
+    }) = &cli.command
+    {
+        history_config.max_entries = max_entries.or(history_config.max_entries);
+        history_config.max_age_days = max_age_days.or(history_config.max_age_days);
+    }
+    cache.enabled = (cache.enabled || cli.cache) && !cli.no_cache;
+    cache.ttl = cli.cache_ttl.unwrap_or(cache.ttl);
+    let profile = file_config.resolve(cli.profile.as_deref())?;
+    let provider_kind = cli
+        .provider
+        .or(profile.provider)
+        .unwrap_or(ProviderKind::Groq);
+
+    let history_filepath = cli.history_filepath.clone();
     let config = llm_chat::Config {
         model: cli.model,
+        default_model: profile
+            .model
+            .unwrap_or_else(|| provider_kind.default_model().to_string()),
         arguments: &cli.prompt,
-        history_filepath: match cli.history_filepath {
+        attachments: match cli.command {
+            // Stdin is only read for a one-shot prompt, login reads the key from it
+            None => input::read_attachments(&cli.files, true, cli.max_input_bytes)?,
+            Some(Commands::Chat) => {
+                input::read_attachments(&cli.files, false, cli.max_input_bytes)?
+            }
+            _ => vec![],
+        },
+        template,
+        template_vars: cli.vars.into_iter().collect(),
+        rag: cli.rag.as_deref().map(rag::Index::load).transpose()?,
+        rag_top: cli.rag_top,
+        history_filepath: match history_filepath.clone() {
             Some(value) => value,
             None => match env::var("HOME") {
-                Ok(value) => value + "/.local/share/llm.json",
+                Ok(value) => value + DEFAULT_HISTORY_FILE,
                 Err(_) => process::exit(1),
             },
         },
-        context: cli.context,
+        history_policy: history_config.policy()?,
+        session: cli.session,
+        system: cli.system,
+        default_system: profile.system,
+        temperature: cli.temperature.or(profile.temperature),
+        max_tokens: cli.max_tokens.or(profile.max_tokens),
+        context: cli.context.unwrap_or(match cli.command {
+            Some(Commands::Chat) => DEFAULT_CHAT_CONTEXT,
+            _ => 0,
+        }),
+        max_context_tokens: cli.max_context_tokens,
         dont_save: cli.dont_save_history,
+        stream: !cli.no_stream,
+        markdown: !cli.no_markdown && (cli.markdown || profile.markdown.unwrap_or(false)),
+        tools,
+        prices,
+        cache: cache.open(),
+        json_schema: cli
+            .json_schema
+            .as_deref()
+            .map(JsonSchema::load)
+            .transpose()?,
+        timeout: Some(Duration::from_secs(cli.timeout)).filter(|t| !t.is_zero()),
+        connect_timeout: Duration::from_secs(cli.connect_timeout),
+        retry: RetryPolicy {
+            max_retries: cli.retries,
+            ..Default::default()
+        },
     };
 
+    if history_filepath.is_none() {
+        migrate_legacy_history(&config)?;
+    }
+
     match &cli.command {
         Some(Commands::ShowHistory { count }) => match llm_chat::show_history(&config, *count) {
             Ok(_) => {}
@@ -87,26 +497,108 @@ async fn main() -> Result<()> {
                 eprintln!("{} occured", err)
             }
         },
+        Some(Commands::SearchHistory { terms, count }) => {
+            llm_chat::search_history(&config, &terms.join(" "), *count)?
+        }
+        Some(Commands::ImportHistory { from, format }) => {
+            llm_chat::import_history(&config, Path::new(from), *format)?
+        }
+        Some(Commands::ExportHistory {
+            format,
+            all,
+            since,
+            until,
+            output,
+        }) => {
+            let format = format
+                .or_else(|| output.as_deref().and_then(ExportFormat::from_path))
+                .unwrap_or(ExportFormat::Markdown);
+            let range = DateRange {
+                since: *since,
+                until: *until,
+            };
+            llm_chat::export_history(&config, *all, range, format, output.as_deref())?
+        }
+        Some(Commands::Prune { .. }) => {
+            llm_chat::prune_history(&config, &config.history_policy.retention)?
+        }
+        Some(Commands::Usage { by, since }) => llm_chat::show_usage(&config, *by, *since)?,
+        Some(Commands::Sessions { action }) => {
+            let mut store = config.open_history()?;
+            match action {
+                SessionAction::List => llm_chat::list_sessions(&config)?,
+                SessionAction::Rename { from, to } => store.rename(from, to)?,
+                SessionAction::Delete { name } => store.delete(name)?,
+                SessionAction::Fork { from, to } => store.fork(from, to)?,
+                SessionAction::Resume { name } => store.resume(name)?,
+                SessionAction::Set {
+                    name,
+                    model,
+                    system,
+                } => {
+                    let session = store.session(name)?.unwrap_or_default();
+                    let model = match model {
+                        Some(model) => Some(model.as_str()).filter(|m| !m.is_empty()),
+                        None => session.model.as_deref(),
+                    };
+                    let system = match system {
+                        Some(system) => Some(system.as_str()).filter(|s| !s.is_empty()),
+                        None => session.system.as_deref(),
+                    };
+                    store.set_settings(name, model, system)?
+                }
+            }
+        }
         Some(Commands::Completion { shell, cmdname }) => {
             let mut cmd = Args::command();
             clap_complete::generate(*shell, &mut cmd, cmdname, &mut std::io::stdout());
         }
+        Some(Commands::Templates) => match &templates_dir {
+            Some(dir) => llm_chat::list_templates(dir)?,
+            None => eprintln!("No templates directory, set HOME or pass --config"),
+        },
+        Some(Commands::Index { dir }) => llm_chat::index_directory(dir)?,
+        Some(Commands::Login { provider, command }) => match &keys_path {
+            Some(path) => llm_chat::login(path, *provider, command.as_deref())?,
+            None => eprintln!("No api key store, set HOME or pass --config"),
+        },
+        Some(Commands::Logout { provider }) => match &keys_path {
+            Some(path) => llm_chat::logout(path, *provider)?,
+            None => eprintln!("No api key store, set HOME or pass --config"),
+        },
+        Some(Commands::Serve { listen }) => {
+            let provider = make_provider(
+                provider_kind,
+                cli.base_url.or(profile.base_url),
+                cli.api_key,
+                profile.api_key_env,
+                keys_path.as_deref(),
+            );
+            llm_chat::serve(&config, provider, *listen).await?
+        }
+        Some(Commands::Chat) => {
+            let provider = make_provider(
+                provider_kind,
+                cli.base_url.or(profile.base_url),
+                cli.api_key,
+                profile.api_key_env,
+                keys_path.as_deref(),
+            );
+            llm_chat::repl::chat(&config, provider.as_ref()).await?
+        }
         None => {
-            let api_key = match cli.api_key {
-                Some(value) => value,
-                None => match env::var("GROQ_API_KEY") {
-                    Ok(value) => value,
-                    Err(_) => {
-                        eprintln!("Error: Please make sure your GROQ_API_KEY is in the environment or provide one with the -a flag");
-                        process::exit(1)
-                    }
-                },
-            };
-            if cli.prompt.is_empty() {
+            if cli.prompt.is_empty() && config.attachments.is_empty() && config.template.is_none() {
                 eprintln!("Error: No prompt was provided");
                 process::exit(1)
             }
-            llm_chat::run(&config, &api_key).await?
+            let provider = make_provider(
+                provider_kind,
+                cli.base_url.or(profile.base_url),
+                cli.api_key,
+                profile.api_key_env,
+                keys_path.as_deref(),
+            );
+            llm_chat::run(&config, provider.as_ref()).await?
         }
     }
 
@@ -0,0 +1,402 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::as_24_bit_terminal_escaped;
+
+const BOLD: &str = "\x1b[1m";
+const NO_BOLD: &str = "\x1b[22m";
+const ITALIC: &str = "\x1b[3m";
+const NO_ITALIC: &str = "\x1b[23m";
+const UNDERLINE: &str = "\x1b[4m";
+const DIM: &str = "\x1b[2m";
+const CODE: &str = "\x1b[36m";
+const NO_CODE: &str = "\x1b[39m";
+const RESET: &str = "\x1b[0m";
+
+const THEME: &str = "base16-ocean.dark";
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    &THEMES.get_or_init(ThemeSet::load_defaults).themes[THEME]
+}
+
+/// Where reply text goes, straight to stdout or through the Markdown renderer
+pub enum Output {
+    Plain,
+    Markdown(Box<MarkdownRenderer>),
+}
+
+impl Output {
+    /// Renders Markdown only when asked to and stdout is a terminal
+    pub fn new(markdown: bool) -> Output {
+        if markdown && io::stdout().is_terminal() {
+            Output::Markdown(Box::default())
+        } else {
+            Output::Plain
+        }
+    }
+
+    pub fn write(&mut self, text: &str) {
+        match self {
+            Output::Plain => print!("{text}"),
+            Output::Markdown(renderer) => print!("{}", renderer.push(text)),
+        }
+        let _ = io::stdout().flush();
+    }
+
+    /// Prints whatever the renderer still holds back
+    pub fn finish(&mut self) {
+        if let Output::Markdown(renderer) = self {
+            print!("{}", renderer.finish());
+        }
+    }
+}
+
+/// Renders Markdown for the terminal a line at a time so streamed replies show up as
+/// they arrive. Incomplete lines are held back until their newline, tables until
+/// their last row.
+#[derive(Default)]
+pub struct MarkdownRenderer {
+    partial: String,
+    /// Highlighter of the fenced code block being rendered
+    code: Option<HighlightLines<'static>>,
+    table: Vec<String>,
+    /// Indent, delimiter and next number of each ordered list being rendered, so
+    /// items can all be written as `1.`
+    ordered: Vec<(usize, char, u64)>,
+}
+
+impl MarkdownRenderer {
+    /// Adds text and returns the rendering of the lines it completed
+    pub fn push(&mut self, text: &str) -> String {
+        self.partial.push_str(text);
+        let mut out = String::new();
+        while let Some(end) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=end).collect();
+            self.line(line.trim_end_matches(['\n', '\r']), &mut out);
+        }
+        out
+    }
+
+    /// Renders the rest of the text, without a trailing newline
+    pub fn finish(&mut self) -> String {
+        let mut out = String::new();
+        let line = std::mem::take(&mut self.partial);
+        if !line.is_empty() {
+            self.line(&line, &mut out);
+        }
+        self.flush_table(&mut out);
+        if self.code.take().is_some() {
+            out.push_str(RESET);
+        }
+        if out.ends_with('\n') {
+            out.pop();
+        }
+        out
+    }
+
+    fn line(&mut self, line: &str, out: &mut String) {
+        if let Some(highlighter) = &mut self.code {
+            if line.trim_start().starts_with("```") {
+                self.code = None;
+                out.push_str(&format!("{RESET}{DIM}{}{RESET}\n", line));
+                return;
+            }
+            let line = format!("{line}\n");
+            let ranges = highlighter
+                .highlight_line(&line, syntaxes())
+                .unwrap_or_default();
+            out.push_str(&as_24_bit_terminal_escaped(&ranges, false));
+            return;
+        }
+
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('|') {
+            self.table.push(trimmed.to_string());
+            return;
+        }
+        self.flush_table(out);
+
+        let indent = &line[..line.len() - trimmed.len()];
+        if !trimmed.is_empty() {
+            // Lists end at the first line that is not indented deeper than their items
+            let keep = if ordered_item(trimmed).is_some() {
+                |list: usize, item: usize| list <= item
+            } else {
+                |list: usize, item: usize| list < item
+            };
+            self.ordered
+                .retain(|(list, _, _)| keep(*list, indent.len()));
+        }
+        if let Some(lang) = trimmed.strip_prefix("```") {
+            let syntax = syntaxes()
+                .find_syntax_by_token(lang.trim())
+                .unwrap_or_else(|| syntaxes().find_syntax_plain_text());
+            self.code = Some(HighlightLines::new(syntax, theme()));
+            out.push_str(&format!("{DIM}{line}{RESET}\n"));
+        } else if let Some(title) = heading(trimmed) {
+            out.push_str(&format!("{BOLD}{UNDERLINE}{}{RESET}\n", inline(title)));
+        } else if is_rule(trimmed) {
+            out.push_str(&format!("{DIM}{}{RESET}\n", "─".repeat(40)));
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            out.push_str(&format!(
+                "{indent}{DIM}│{RESET} {}\n",
+                inline(quote.trim_start())
+            ));
+        } else if let Some(item) = ["- ", "* ", "+ "]
+            .iter()
+            .find_map(|bullet| trimmed.strip_prefix(bullet))
+        {
+            out.push_str(&format!("{indent}• {}\n", inline(item)));
+        } else if let Some((number, delimiter, item)) = ordered_item(trimmed) {
+            // A new delimiter starts a new list
+            let number = match self.ordered.last_mut() {
+                Some((list, current, next)) if *list == indent.len() && *current == delimiter => {
+                    next
+                }
+                _ => {
+                    self.ordered.retain(|(list, _, _)| *list < indent.len());
+                    self.ordered.push((indent.len(), delimiter, number));
+                    &mut self.ordered.last_mut().unwrap().2
+                }
+            };
+            out.push_str(&format!(
+                "{indent}{BOLD}{number}{delimiter}{NO_BOLD} {}\n",
+                inline(item)
+            ));
+            *number += 1;
+        } else {
+            out.push_str(&format!("{}\n", inline(line)));
+        }
+    }
+
+    fn flush_table(&mut self, out: &mut String) {
+        if self.table.is_empty() {
+            return;
+        }
+        let rows: Vec<Vec<String>> = std::mem::take(&mut self.table)
+            .iter()
+            .map(|row| {
+                let row = row.trim().trim_start_matches('|');
+                let row = row.strip_suffix('|').unwrap_or(row);
+                row.split('|').map(|cell| inline(cell.trim())).collect()
+            })
+            .filter(|cells: &Vec<String>| !is_separator(cells))
+            .collect();
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|i| {
+                rows.iter()
+                    .filter_map(|row| row.get(i))
+                    .map(|cell| visible_width(cell))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        for (n, row) in rows.iter().enumerate() {
+            let cells: Vec<String> = widths
+                .iter()
+                .enumerate()
+                .map(|(i, width)| {
+                    let cell = row.get(i).map_or("", String::as_str);
+                    let padding = " ".repeat(width - visible_width(cell));
+                    if n == 0 {
+                        format!("{BOLD}{cell}{NO_BOLD}{padding}")
+                    } else {
+                        format!("{cell}{padding}")
+                    }
+                })
+                .collect();
+            out.push_str(cells.join(" │ ").trim_end());
+            out.push('\n');
+            if n == 0 {
+                let rule: Vec<String> = widths.iter().map(|w| "─".repeat(*w)).collect();
+                out.push_str(&format!("{}\n", rule.join("─┼─")));
+            }
+        }
+    }
+}
+
+fn heading(line: &str) -> Option<&str> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    line[level..].strip_prefix(' ')
+}
+
+/// Number, delimiter and text of a `1. item` or `1) item` line
+fn ordered_item(line: &str) -> Option<(u64, char, &str)> {
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    if !(1..=9).contains(&digits) {
+        return None;
+    }
+    let delimiter = line[digits..]
+        .chars()
+        .next()
+        .filter(|c| matches!(c, '.' | ')'))?;
+    let item = line[digits + 1..].strip_prefix(' ')?;
+    Some((line[..digits].parse().ok()?, delimiter, item))
+}
+
+fn is_rule(line: &str) -> bool {
+    let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    line.len() >= 3
+        && ['-', '*', '_']
+            .iter()
+            .any(|marker| line.chars().all(|c| c == *marker))
+}
+
+/// The `|---|:---:|` row under a table header
+fn is_separator(cells: &[String]) -> bool {
+    cells.iter().all(|cell| {
+        !cell.is_empty() && cell.contains('-') && cell.chars().all(|c| matches!(c, '-' | ':'))
+    })
+}
+
+/// Width of the text without escape sequences
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| *c == 'm');
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+/// Styles bold, italic and code spans. Markers without a closing one later on the
+/// line are left as they are.
+fn inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let rest = |i: usize| chars[i..].iter().collect::<String>();
+    let mut out = String::new();
+    let (mut bold, mut italic) = (false, false);
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let prev = i.checked_sub(1).map(|p| chars[p]);
+        if c == '`' {
+            if let Some(len) = chars[i + 1..].iter().position(|c| *c == '`') {
+                let code: String = chars[i + 1..i + 1 + len].iter().collect();
+                out.push_str(&format!("{CODE}{code}{NO_CODE}"));
+                i += len + 2;
+                continue;
+            }
+        } else if (c == '*' || c == '_') && next == Some(c) {
+            let marker: String = [c, c].iter().collect();
+            if bold || rest(i + 2).contains(&marker) {
+                bold = !bold;
+                out.push_str(if bold { BOLD } else { NO_BOLD });
+                i += 2;
+                continue;
+            }
+        } else if c == '*' || c == '_' {
+            // `_` inside a word like snake_case is not emphasis
+            let word_char = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+            let opens = !italic
+                && next.is_some_and(|n| !n.is_whitespace())
+                && (c == '*' || !word_char(prev))
+                && rest(i + 1).contains(c);
+            let closes = italic && (c == '*' || !word_char(next));
+            if opens || closes {
+                italic = !italic;
+                out.push_str(if italic { ITALIC } else { NO_ITALIC });
+                i += 1;
+                continue;
+            }
+        }
+        out.push(c);
+        i += 1;
+    }
+    if bold {
+        out.push_str(NO_BOLD);
+    }
+    if italic {
+        out.push_str(NO_ITALIC);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_styles() {
+        assert_eq!(
+            inline("**bold** and *it* `a*b`"),
+            format!("{BOLD}bold{NO_BOLD} and {ITALIC}it{NO_ITALIC} {CODE}a*b{NO_CODE}")
+        );
+        assert_eq!(
+            inline("snake_case_name and 2 * 3"),
+            "snake_case_name and 2 * 3"
+        );
+    }
+
+    #[test]
+    fn lines_are_rendered_when_complete() {
+        let mut renderer = MarkdownRenderer::default();
+        assert_eq!(renderer.push("# Ti"), "");
+        assert_eq!(
+            renderer.push("tle\n- item"),
+            format!("{BOLD}{UNDERLINE}Title{RESET}\n")
+        );
+        assert_eq!(renderer.finish(), "• item");
+    }
+
+    #[test]
+    fn ordered_lists_are_numbered() {
+        let mut renderer = MarkdownRenderer::default();
+        let out = renderer.push(
+            "1. **one**\n1. two\n   - sub\n   more\n\n1. three\n\nText\n4) four\n1) five\n1. one\n",
+        );
+        assert_eq!(
+            out,
+            format!(
+                "{BOLD}1.{NO_BOLD} {BOLD}one{NO_BOLD}\n\
+                 {BOLD}2.{NO_BOLD} two\n   • sub\n   more\n\n\
+                 {BOLD}3.{NO_BOLD} three\n\nText\n{BOLD}4){NO_BOLD} four\n\
+                 {BOLD}5){NO_BOLD} five\n{BOLD}1.{NO_BOLD} one\n"
+            )
+        );
+    }
+
+    #[test]
+    fn tables_are_aligned() {
+        let mut renderer = MarkdownRenderer::default();
+        let out = renderer.push("| a | long |\n|---|:---:|\n| wide cell | b |\n");
+        assert_eq!(out, "");
+        assert_eq!(
+            renderer.finish(),
+            format!(
+                "{BOLD}a{NO_BOLD}         │ {BOLD}long{NO_BOLD}\n\
+                 ──────────┼─────\n\
+                 wide cell │ b"
+            )
+        );
+    }
+
+    #[test]
+    fn code_blocks_are_highlighted() {
+        let mut renderer = MarkdownRenderer::default();
+        let out = renderer.push("```rust\nlet x = 1; // *not italic*\n```\nafter\n");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].contains("\x1b[38;2;"));
+        assert!(lines[1].contains("*not italic*"));
+        assert_eq!(lines[3], "after");
+    }
+}
@@ -0,0 +1,717 @@
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+
+use crate::stream::StreamFormat;
+use crate::tools::ToolCall;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const ANTHROPIC_MAX_TOKENS: u32 = 4096;
+
+/// Backends that can be selected with `--provider` or the config file
+#[derive(ValueEnum, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    /// Groq's OpenAI-compatible endpoint
+    Groq,
+    /// OpenAI or any other OpenAI-compatible server (use --base-url)
+    Openai,
+    /// Anthropic Messages API
+    Anthropic,
+    /// Local Ollama server
+    Ollama,
+}
+
+impl ProviderKind {
+    /// Name used on the command line and in the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProviderKind::Groq => "groq",
+            ProviderKind::Openai => "openai",
+            ProviderKind::Anthropic => "anthropic",
+            ProviderKind::Ollama => "ollama",
+        }
+    }
+
+    pub fn default_base_url(&self) -> &'static str {
+        match self {
+            ProviderKind::Groq => "https://api.groq.com/openai/v1",
+            ProviderKind::Openai => "https://api.openai.com/v1",
+            ProviderKind::Anthropic => "https://api.anthropic.com/v1",
+            ProviderKind::Ollama => "http://localhost:11434",
+        }
+    }
+
+    pub fn default_model(&self) -> &'static str {
+        match self {
+            ProviderKind::Groq => "llama-3.3-70b-versatile",
+            ProviderKind::Openai => "gpt-4o-mini",
+            ProviderKind::Anthropic => "claude-3-5-haiku-latest",
+            ProviderKind::Ollama => "llama3.2",
+        }
+    }
+
+    /// Environment variable the api key is read from, `None` if no key is needed
+    pub fn api_key_env(&self) -> Option<&'static str> {
+        match self {
+            ProviderKind::Groq => Some("GROQ_API_KEY"),
+            ProviderKind::Openai => Some("OPENAI_API_KEY"),
+            ProviderKind::Anthropic => Some("ANTHROPIC_API_KEY"),
+            ProviderKind::Ollama => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ChatMessage {
+    pub role: String,
+    /// OpenAI sends `null` alongside tool calls
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Call a `tool` message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: impl Into<String>) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.into(),
+            tool_calls: vec![],
+            tool_call_id: None,
+        }
+    }
+
+    /// Output of a tool, sent back to the model
+    pub fn tool_result(call_id: &str, content: String) -> ChatMessage {
+        ChatMessage {
+            tool_call_id: Some(call_id.to_string()),
+            ..ChatMessage::new("tool", content)
+        }
+    }
+}
+
+fn null_as_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Token counts reported by the api
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    /// Sum of the counts, `None` only if neither side has any
+    pub fn combine(a: Option<Usage>, b: Option<Usage>) -> Option<Usage> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(Usage {
+                prompt_tokens: a.prompt_tokens + b.prompt_tokens,
+                completion_tokens: a.completion_tokens + b.completion_tokens,
+            }),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// A complete, non-streamed response
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Reply {
+    pub message: ChatMessage,
+    pub usage: Option<Usage>,
+}
+
+/// What one event of a streamed response carries
+#[derive(Debug, Default, PartialEq)]
+pub struct StreamDelta {
+    pub text: Option<String>,
+    pub usage: Option<Usage>,
+}
+
+pub struct ChatRequest<'a> {
+    pub model: &'a str,
+    pub messages: &'a [ChatMessage],
+    pub stream: bool,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+    /// Tool definitions in the OpenAI format, only sent by providers that support tools
+    pub tools: &'a [Value],
+    /// Schema the reply should match, for providers with a JSON mode
+    pub json_schema: Option<&'a Value>,
+}
+
+/// A chat completion backend
+pub trait Provider: Send + Sync {
+    /// Root of the api, also tells cached replies of different servers apart
+    fn base_url(&self) -> &str;
+
+    /// Builds the HTTP request for a chat completion
+    fn build_request(&self, client: &Client, req: &ChatRequest) -> RequestBuilder;
+
+    /// Extracts the assistant message, with any tool calls, and the token usage
+    /// from a response body
+    fn parse_response(&self, body: Value) -> Result<Reply>;
+
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    fn stream_format(&self) -> StreamFormat {
+        StreamFormat::Sse
+    }
+
+    /// Extracts the text delta and token usage from one event of a streamed response,
+    /// most events carry only one of them
+    fn parse_stream_event(&self, data: &str) -> Result<StreamDelta>;
+}
+
+pub fn build_provider(
+    kind: ProviderKind,
+    base_url: Option<String>,
+    api_key: Option<String>,
+) -> Box<dyn Provider> {
+    let base_url = base_url
+        .unwrap_or_else(|| kind.default_base_url().to_string())
+        .trim_end_matches('/')
+        .to_string();
+    match kind {
+        ProviderKind::Groq | ProviderKind::Openai => {
+            Box::new(OpenAiCompatible { base_url, api_key })
+        }
+        ProviderKind::Anthropic => Box::new(Anthropic {
+            base_url,
+            api_key: api_key.unwrap_or_default(),
+        }),
+        ProviderKind::Ollama => Box::new(Ollama { base_url }),
+    }
+}
+
+pub struct OpenAiCompatible {
+    base_url: String,
+    api_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiResponse {
+    choices: Vec<OpenAiChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiChoice {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize)]
+struct OpenAiChunk {
+    choices: Vec<OpenAiChunkChoice>,
+    /// Only in the last chunk, when `stream_options.include_usage` is set
+    usage: Option<Usage>,
+    /// Groq reports the usage of a stream here instead
+    x_groq: Option<GroqExtra>,
+}
+
+#[derive(Deserialize)]
+struct GroqExtra {
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiChunkChoice {
+    delta: OpenAiDelta,
+}
+
+#[derive(Deserialize)]
+struct OpenAiDelta {
+    content: Option<String>,
+}
+
+impl Provider for OpenAiCompatible {
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn build_request(&self, client: &Client, req: &ChatRequest) -> RequestBuilder {
+        let mut body = json!({
+            "model": req.model,
+            "messages": req.messages,
+            "stream": req.stream,
+        });
+        if req.stream {
+            body["stream_options"] = json!({"include_usage": true});
+        }
+        if let Some(temperature) = req.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(max_tokens) = req.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if !req.tools.is_empty() {
+            body["tools"] = json!(req.tools);
+        }
+        if let Some(schema) = req.json_schema {
+            body["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": {"name": "response", "schema": schema},
+            });
+        }
+        let builder = client
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(&body);
+        match &self.api_key {
+            Some(key) => builder.bearer_auth(key),
+            None => builder,
+        }
+    }
+
+    fn parse_response(&self, body: Value) -> Result<Reply> {
+        let res: OpenAiResponse = serde_json::from_value(body)?;
+        let message = res
+            .choices
+            .into_iter()
+            .next()
+            .map(|c| c.message)
+            .ok_or_else(|| anyhow!("Response contained no choices"))?;
+        Ok(Reply {
+            message,
+            usage: res.usage,
+        })
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    fn parse_stream_event(&self, data: &str) -> Result<StreamDelta> {
+        let chunk: OpenAiChunk = serde_json::from_str(data)?;
+        Ok(StreamDelta {
+            text: chunk
+                .choices
+                .into_iter()
+                .next()
+                .and_then(|c| c.delta.content),
+            usage: chunk.usage.or(chunk.x_groq.and_then(|x| x.usage)),
+        })
+    }
+}
+
+pub struct Anthropic {
+    base_url: String,
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct AnthropicResponse {
+    content: Vec<AnthropicBlock>,
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Deserialize, Default)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+impl From<AnthropicUsage> for Usage {
+    fn from(usage: AnthropicUsage) -> Usage {
+        Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AnthropicBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicEvent {
+    /// Carries the input token count
+    MessageStart {
+        message: AnthropicMessageStart,
+    },
+    ContentBlockDelta {
+        delta: AnthropicBlockDelta,
+    },
+    /// Carries the final output token count
+    MessageDelta {
+        usage: AnthropicUsage,
+    },
+    Error {
+        error: AnthropicError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct AnthropicMessageStart {
+    #[serde(default)]
+    usage: AnthropicUsage,
+}
+
+#[derive(Deserialize)]
+struct AnthropicBlockDelta {
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AnthropicError {
+    message: String,
+}
+
+impl Provider for Anthropic {
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn build_request(&self, client: &Client, req: &ChatRequest) -> RequestBuilder {
+        // The Messages API takes the system prompt as a top level field
+        let (system, messages): (Vec<&ChatMessage>, Vec<&ChatMessage>) =
+            req.messages.iter().partition(|m| m.role == "system");
+        let mut body = json!({
+            "model": req.model,
+            "max_tokens": req.max_tokens.unwrap_or(ANTHROPIC_MAX_TOKENS),
+            "messages": messages,
+            "stream": req.stream,
+        });
+        if let Some(temperature) = req.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if !system.is_empty() {
+            let system: Vec<&str> = system.iter().map(|m| m.content.as_str()).collect();
+            body["system"] = json!(system.join("\n"));
+        }
+        client
+            .post(format!("{}/messages", self.base_url))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&body)
+    }
+
+    fn parse_response(&self, body: Value) -> Result<Reply> {
+        let res: AnthropicResponse = serde_json::from_value(body)?;
+        let text: String = res
+            .content
+            .into_iter()
+            .filter(|b| b.kind == "text")
+            .map(|b| b.text)
+            .collect();
+        Ok(Reply {
+            message: ChatMessage::new("assistant", text),
+            usage: res.usage.map(Usage::from),
+        })
+    }
+
+    fn parse_stream_event(&self, data: &str) -> Result<StreamDelta> {
+        // Input and output counts come in separate events so they can be summed
+        Ok(match serde_json::from_str(data)? {
+            AnthropicEvent::MessageStart { message } => StreamDelta {
+                text: None,
+                usage: Some(Usage {
+                    prompt_tokens: message.usage.input_tokens,
+                    completion_tokens: 0,
+                }),
+            },
+            AnthropicEvent::ContentBlockDelta { delta } => StreamDelta {
+                text: delta.text,
+                usage: None,
+            },
+            AnthropicEvent::MessageDelta { usage } => StreamDelta {
+                text: None,
+                usage: Some(Usage {
+                    prompt_tokens: 0,
+                    completion_tokens: usage.output_tokens,
+                }),
+            },
+            AnthropicEvent::Error { error } => bail!("{}", error.message),
+            AnthropicEvent::Other => StreamDelta::default(),
+        })
+    }
+}
+
+pub struct Ollama {
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+struct OllamaResponse {
+    message: ChatMessage,
+    /// Token counts, only in the last event of a stream
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+impl OllamaResponse {
+    fn usage(&self) -> Option<Usage> {
+        if self.prompt_eval_count.is_none() && self.eval_count.is_none() {
+            return None;
+        }
+        Some(Usage {
+            prompt_tokens: self.prompt_eval_count.unwrap_or(0),
+            completion_tokens: self.eval_count.unwrap_or(0),
+        })
+    }
+}
+
+impl Provider for Ollama {
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn build_request(&self, client: &Client, req: &ChatRequest) -> RequestBuilder {
+        let mut options = json!({});
+        if let Some(temperature) = req.temperature {
+            options["temperature"] = json!(temperature);
+        }
+        if let Some(max_tokens) = req.max_tokens {
+            options["num_predict"] = json!(max_tokens);
+        }
+        let mut body = json!({
+            "model": req.model,
+            "messages": req.messages,
+            "stream": req.stream,
+            "options": options,
+        });
+        if let Some(schema) = req.json_schema {
+            body["format"] = schema.clone();
+        }
+        client
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&body)
+    }
+
+    fn parse_response(&self, body: Value) -> Result<Reply> {
+        let res: OllamaResponse = serde_json::from_value(body)?;
+        Ok(Reply {
+            usage: res.usage(),
+            message: res.message,
+        })
+    }
+
+    fn stream_format(&self) -> StreamFormat {
+        StreamFormat::JsonLines
+    }
+
+    fn parse_stream_event(&self, data: &str) -> Result<StreamDelta> {
+        let res: OllamaResponse = serde_json::from_str(data)?;
+        Ok(StreamDelta {
+            usage: res.usage(),
+            text: Some(res.message.content).filter(|c| !c.is_empty()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    fn messages() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::new("system", "be brief"),
+            ChatMessage::new("user", "hi"),
+        ]
+    }
+
+    async fn complete(provider: &dyn Provider) -> Result<String> {
+        let messages = messages();
+        let req = ChatRequest {
+            model: "test-model",
+            messages: &messages,
+            stream: false,
+            temperature: Some(0.5),
+            max_tokens: Some(100),
+            tools: &[],
+            json_schema: None,
+        };
+        let body = provider
+            .build_request(&Client::new(), &req)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(provider.parse_response(body)?.message.content)
+    }
+
+    #[tokio::test]
+    async fn openai_compatible() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/v1/chat/completions")
+            .match_header("authorization", "Bearer secret")
+            .match_body(Matcher::PartialJson(json!({
+                "model": "test-model",
+                "temperature": 0.5,
+                "max_tokens": 100,
+            })))
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"hello"}}]}"#)
+            .create_async()
+            .await;
+
+        let provider = build_provider(
+            ProviderKind::Openai,
+            Some(format!("{}/v1/", server.url())),
+            Some("secret".to_string()),
+        );
+        assert_eq!(complete(provider.as_ref()).await.unwrap(), "hello");
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn anthropic() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/messages")
+            .match_header("x-api-key", "secret")
+            .match_header("anthropic-version", ANTHROPIC_VERSION)
+            .match_body(Matcher::PartialJson(json!({
+                "system": "be brief",
+                "messages": [{"role": "user", "content": "hi"}],
+            })))
+            .with_body(r#"{"content":[{"type":"text","text":"hel"},{"type":"text","text":"lo"}]}"#)
+            .create_async()
+            .await;
+
+        let provider = build_provider(
+            ProviderKind::Anthropic,
+            Some(server.url()),
+            Some("secret".to_string()),
+        );
+        assert_eq!(complete(provider.as_ref()).await.unwrap(), "hello");
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn ollama() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/api/chat")
+            .match_body(Matcher::PartialJson(json!({
+                "stream": false,
+                "options": {"temperature": 0.5, "num_predict": 100},
+            })))
+            .with_body(r#"{"message":{"role":"assistant","content":"hello"},"done":true}"#)
+            .create_async()
+            .await;
+
+        let provider = build_provider(ProviderKind::Ollama, Some(server.url()), None);
+        assert_eq!(complete(provider.as_ref()).await.unwrap(), "hello");
+        mock.assert_async().await;
+    }
+
+    #[test]
+    fn stream_events() {
+        let openai = build_provider(ProviderKind::Openai, None, None);
+        assert_eq!(
+            openai
+                .parse_stream_event(r#"{"choices":[{"delta":{"content":"he"}}]}"#)
+                .unwrap()
+                .text,
+            Some("he".to_string())
+        );
+        assert_eq!(
+            openai
+                .parse_stream_event(r#"{"choices":[{"delta":{"role":"assistant"}}]}"#)
+                .unwrap(),
+            StreamDelta::default()
+        );
+
+        let anthropic = build_provider(ProviderKind::Anthropic, None, None);
+        assert_eq!(
+            anthropic
+                .parse_stream_event(
+                    r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"llo"}}"#
+                )
+                .unwrap()
+                .text,
+            Some("llo".to_string())
+        );
+        assert_eq!(
+            anthropic.parse_stream_event(r#"{"type":"ping"}"#).unwrap(),
+            StreamDelta::default()
+        );
+        assert!(anthropic
+            .parse_stream_event(
+                r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn empty_choices_is_an_error() {
+        let provider = build_provider(ProviderKind::Groq, None, None);
+        assert!(provider.parse_response(json!({"choices": []})).is_err());
+    }
+
+    #[test]
+    fn tool_calls_are_parsed() {
+        let provider = build_provider(ProviderKind::Openai, None, None);
+        let Reply { message, usage } = provider
+            .parse_response(json!({"choices": [{"message": {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{"id": "call_1", "type": "function", "function": {
+                    "name": "list_dir", "arguments": "{\"path\":\".\"}"
+                }}],
+            }}]}))
+            .unwrap();
+        assert_eq!(usage, None);
+        assert_eq!(message.content, "");
+        assert_eq!(message.tool_calls[0].function.name, "list_dir");
+    }
+
+    #[test]
+    fn usage_is_reported() {
+        let openai = build_provider(ProviderKind::Openai, None, None);
+        let reply = openai
+            .parse_response(json!({
+                "choices": [{"message": {"role": "assistant", "content": "hi"}}],
+                "usage": {"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15},
+            }))
+            .unwrap();
+        let expected = Usage {
+            prompt_tokens: 12,
+            completion_tokens: 3,
+        };
+        assert_eq!(reply.usage, Some(expected));
+        let groq_last_chunk =
+            r#"{"choices":[],"x_groq":{"usage":{"prompt_tokens":12,"completion_tokens":3}}}"#;
+        assert_eq!(
+            openai.parse_stream_event(groq_last_chunk).unwrap().usage,
+            Some(expected)
+        );
+
+        let anthropic = build_provider(ProviderKind::Anthropic, None, None);
+        let events = [
+            r#"{"type":"message_start","message":{"id":"m","usage":{"input_tokens":12,"output_tokens":1}}}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":3}}"#,
+        ];
+        let usage = events.iter().fold(None, |total, event| {
+            Usage::combine(total, anthropic.parse_stream_event(event).unwrap().usage)
+        });
+        assert_eq!(usage, Some(expected));
+
+        let ollama = build_provider(ProviderKind::Ollama, None, None);
+        let last = r#"{"message":{"role":"assistant","content":""},"done":true,"prompt_eval_count":12,"eval_count":3}"#;
+        assert_eq!(
+            ollama.parse_stream_event(last).unwrap().usage,
+            Some(expected)
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cache;
+
+/// Files with these extensions are indexed, everything else is skipped
+const EXTENSIONS: &[&str] = &["md", "markdown", "txt", "rst", "adoc", "org"];
+/// A chunk ends at the first paragraph break after this many words
+const CHUNK_WORDS: usize = 200;
+/// BM25 term frequency saturation
+const K1: f64 = 1.2;
+/// BM25 length normalization
+const B: f64 = 0.75;
+/// Chunks added to a prompt when `--rag-top` is not given
+pub const DEFAULT_TOP: usize = 4;
+
+/// Where the index of `dir` is kept, `index/<hash of the directory>.json` in the
+/// cache so the indexed directory is never written to
+pub fn index_path(dir: &Path) -> Result<PathBuf> {
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+    let hash: String = Sha256::digest(dir.to_string_lossy().as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    let home = cache::cache_home().context("No cache directory, set HOME or XDG_CACHE_HOME")?;
+    Ok(home.join("index").join(format!("{hash}.json")))
+}
+
+/// Lowercased runs of letters and digits, single characters are dropped
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().nth(1).is_some())
+        .map(str::to_lowercase)
+}
+
+/// Part of a file, cut at paragraph breaks and before Markdown headings
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Chunk {
+    /// Path relative to the indexed directory
+    pub source: String,
+    /// First and last line, counting from 1
+    pub lines: (usize, usize),
+    pub text: String,
+    /// How often each term occurs in the chunk
+    terms: BTreeMap<String, u32>,
+    /// Number of terms in the chunk
+    len: u32,
+}
+
+impl Chunk {
+    fn new(source: &str, lines: (usize, usize), text: String) -> Chunk {
+        let mut terms = BTreeMap::new();
+        let mut len = 0;
+        for term in tokenize(&text) {
+            *terms.entry(term).or_insert(0) += 1;
+            len += 1;
+        }
+        Chunk {
+            source: source.to_string(),
+            lines,
+            text,
+            terms,
+            len,
+        }
+    }
+}
+
+fn chunk_file(source: &str, content: &str) -> Vec<Chunk> {
+    let mut chunks = vec![];
+    let mut text = String::new();
+    let mut start = 0;
+    let mut words = 0;
+    let mut in_fence = false;
+    let mut flush = |text: &mut String, start: usize, end: usize| {
+        if !text.trim().is_empty() {
+            chunks.push(Chunk::new(source, (start, end), text.trim().to_string()));
+        }
+        text.clear();
+    };
+    for (i, line) in content.lines().enumerate() {
+        let number = i + 1;
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+        }
+        let heading = !in_fence && line.starts_with('#');
+        let paragraph_end = !in_fence && line.trim().is_empty();
+        if (heading || (paragraph_end && words >= CHUNK_WORDS)) && !text.trim().is_empty() {
+            flush(&mut text, start, number - 1);
+            words = 0;
+        }
+        if text.trim().is_empty() {
+            text.clear();
+            start = number;
+        }
+        words += line.split_whitespace().count();
+        text.push_str(line);
+        text.push('\n');
+    }
+    let end = content.lines().count();
+    flush(&mut text, start, end);
+    chunks
+}
+
+/// Indexable files under `dir` sorted by path, hidden files and directories are
+/// skipped
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries =
+        fs::read_dir(dir).with_context(|| format!("Failed to read directory {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(())
+}
+
+/// A chunk that matched a query
+pub struct Hit<'a> {
+    pub chunk: &'a Chunk,
+    pub score: f64,
+}
+
+/// BM25 keyword index over the chunks of the text and Markdown files in a directory
+#[derive(Serialize, Deserialize)]
+pub struct Index {
+    /// The directory as given, sources are cited relative to it
+    #[serde(skip)]
+    root: PathBuf,
+    pub files: usize,
+    pub chunks: Vec<Chunk>,
+    /// Number of chunks each term occurs in
+    doc_freq: BTreeMap<String, u32>,
+}
+
+impl Index {
+    pub fn build(dir: &Path) -> Result<Index> {
+        let mut files = vec![];
+        collect_files(dir, &mut files)?;
+        let mut chunks = vec![];
+        let mut indexed = 0;
+        for path in &files {
+            let Ok(content) = fs::read_to_string(path) else {
+                eprintln!("Skipping {}, it is not UTF-8 text", path.display());
+                continue;
+            };
+            let source = path.strip_prefix(dir).unwrap_or(path).to_string_lossy();
+            chunks.extend(chunk_file(&source, &content));
+            indexed += 1;
+        }
+        Ok(Index::new(dir, indexed, chunks))
+    }
+
+    fn new(root: &Path, files: usize, chunks: Vec<Chunk>) -> Index {
+        let mut doc_freq = BTreeMap::new();
+        for term in chunks.iter().flat_map(|chunk| chunk.terms.keys()) {
+            *doc_freq.entry(term.clone()).or_insert(0) += 1;
+        }
+        Index {
+            root: root.to_path_buf(),
+            files,
+            chunks,
+            doc_freq,
+        }
+    }
+
+    /// Writes the index to [`index_path`], returning the path
+    pub fn save(&self) -> Result<PathBuf> {
+        let path = index_path(&self.root)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        fs::write(&path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write index {}", path.display()))?;
+        Ok(path)
+    }
+
+    pub fn load(dir: &Path) -> Result<Index> {
+        let path = index_path(dir)?;
+        if !path.exists() {
+            bail!(
+                "{} is not indexed, run `llm_chat index {}` first",
+                dir.display(),
+                dir.display()
+            );
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read index {}", path.display()))?;
+        let mut index: Index = serde_json::from_str(&content).with_context(|| {
+            format!(
+                "Failed to parse index {}, run `llm_chat index` again",
+                path.display()
+            )
+        })?;
+        index.root = dir.to_path_buf();
+        Ok(index)
+    }
+
+    /// The `top` chunks with the highest BM25 score for the query, best first.
+    /// Chunks sharing no term with the query are never returned.
+    pub fn search(&self, query: &str, top: usize) -> Vec<Hit<'_>> {
+        let mut terms: Vec<String> = tokenize(query).collect();
+        terms.sort();
+        terms.dedup();
+        let count = self.chunks.len() as f64;
+        let avg_len = self.chunks.iter().map(|c| c.len as f64).sum::<f64>() / count.max(1.0);
+        let mut hits: Vec<Hit> = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let score = terms
+                    .iter()
+                    .filter_map(|term| {
+                        let tf = *chunk.terms.get(term)? as f64;
+                        let df = *self.doc_freq.get(term)? as f64;
+                        let idf = ((count - df + 0.5) / (df + 0.5) + 1.0).ln();
+                        let norm = K1 * (1.0 - B + B * chunk.len as f64 / avg_len);
+                        Some(idf * tf * (K1 + 1.0) / (tf + norm))
+                    })
+                    .sum();
+                Hit { chunk, score }
+            })
+            .filter(|hit| hit.score > 0.0)
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(top);
+        hits
+    }
+
+    /// `path:first-last` of the chunk, relative to the current directory
+    pub fn citation(&self, chunk: &Chunk) -> String {
+        let (first, last) = chunk.lines;
+        format!("{}:{first}-{last}", self.root.join(&chunk.source).display())
+    }
+
+    /// The prompt preceded by the hits, numbered so the reply can cite them
+    pub fn with_context(&self, prompt: &str, hits: &[Hit]) -> String {
+        if hits.is_empty() {
+            return prompt.to_string();
+        }
+        let mut out = String::from(
+            "Use the numbered sources below where they help answer the question \
+             and cite them like [1].\n\n",
+        );
+        for (i, hit) in hits.iter().enumerate() {
+            out.push_str(&format!(
+                "[{}] {}\n{}\n\n",
+                i + 1,
+                self.citation(hit.chunk),
+                hit.chunk.text
+            ));
+        }
+        out.push_str(&format!("Question: {}", prompt.trim()));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_ranked_by_bm25() {
+        let content = "# Install\n\nRun cargo install llm_chat.\n\n\
+                       # Config\n\nThe config file sets the provider and the model.\n\
+                       ```\n# not a heading\n```\n\n\
+                       # Cache\n\nReplies are cached for a week, the config sets the ttl.\n";
+        let chunks = chunk_file("guide.md", content);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| (chunk.lines, chunk.text.lines().next().unwrap()))
+                .collect::<Vec<_>>(),
+            [
+                ((1, 4), "# Install"),
+                ((5, 11), "# Config"),
+                ((12, 14), "# Cache")
+            ]
+        );
+
+        let index = Index::new(Path::new("docs"), 1, chunks);
+        let hits = index.search("How long are replies cached? Which config?", 2);
+        assert_eq!(hits.len(), 2);
+        assert_eq!(index.citation(hits[0].chunk), "docs/guide.md:12-14");
+        assert_eq!(hits[1].chunk.lines, (5, 11));
+        assert!(index.search("unrelated words", 2).is_empty());
+
+        let prompt = index.with_context("How long?", &hits[..1]);
+        assert!(
+            prompt.contains("[1] docs/guide.md:12-14\n# Cache\n"),
+            "{prompt}"
+        );
+        assert!(prompt.ends_with("Question: How long?"), "{prompt}");
+    }
+}
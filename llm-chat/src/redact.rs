@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::history::Entry;
+
+/// Text that replaces every match
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Api keys of the common providers and email addresses
+pub const DEFAULT_PATTERNS: &[&str] = &[
+    // OpenAI, Anthropic and compatible providers
+    r"\bsk-[A-Za-z0-9_-]{20,}",
+    // Groq
+    r"\bgsk_[A-Za-z0-9]{20,}",
+    // GitHub tokens
+    r"\bgh[pousr]_[A-Za-z0-9]{36,}",
+    r"\bgithub_pat_[A-Za-z0-9_]{22,}",
+    // AWS access key ids
+    r"\bAKIA[0-9A-Z]{16}\b",
+    // Slack tokens
+    r"\bxox[abposr]-[A-Za-z0-9-]{10,}",
+    // Authorization headers
+    r"(?i)\bbearer\s+[A-Za-z0-9._~+/-]{20,}=*",
+    // Email addresses
+    r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}",
+];
+
+/// Replaces secrets in entries before they are saved to the history
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    /// The default patterns when `defaults` is set, followed by `extra`
+    pub fn new(defaults: bool, extra: &[String]) -> Result<Redactor> {
+        let defaults = DEFAULT_PATTERNS.iter().copied().filter(|_| defaults);
+        let patterns = defaults
+            .chain(extra.iter().map(String::as_str))
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .with_context(|| format!("Invalid redaction pattern '{pattern}'"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Redactor { patterns })
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for pattern in &self.patterns {
+            text = pattern.replace_all(&text, REDACTED).into_owned();
+        }
+        text
+    }
+
+    /// The entry with its prompt, response and tool log redacted
+    pub fn entry(&self, entry: &Entry) -> Entry {
+        let mut entry = entry.clone();
+        if self.patterns.is_empty() {
+            return entry;
+        }
+        entry.prompt = self.redact(&entry.prompt);
+        entry.response = self.redact(&entry.response);
+        for message in &mut entry.tool_log {
+            message.content = self.redact(&message.content);
+            for call in &mut message.tool_calls {
+                call.function.arguments = self.redact(&call.function.arguments);
+            }
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted() {
+        let redactor = Redactor::new(true, &[r"hunter\d".to_string()]).unwrap();
+        assert_eq!(
+            redactor.redact(
+                "key gsk_0123456789abcdefghijKLMN and sk-proj-abcdefghij0123456789, \
+                 mail jane.doe@example.co.uk, password hunter2, task-list stays"
+            ),
+            "key [REDACTED] and [REDACTED], mail [REDACTED], password [REDACTED], \
+             task-list stays"
+        );
+        assert_eq!(
+            Redactor::new(false, &[]).unwrap().redact("a@b.io"),
+            "a@b.io"
+        );
+        assert!(Redactor::new(false, &["(".to_string()]).is_err());
+    }
+}
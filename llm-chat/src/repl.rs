@@ -0,0 +1,171 @@
+use anyhow::Result;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::provider::{ChatRequest, Provider};
+use crate::{build_messages, input, respond, session_settings, with_sources, Config};
+
+const BLOCK_DELIMITER: &str = "\"\"\"";
+
+const HELP: &str = "\
+/model [name]     show or change the model
+/system [prompt]  show or change the system prompt, `/system -` removes it
+/context [n]      show or change the number of previous turns sent
+/clear            forget the conversation so far
+/save             store the model and system prompt in the session
+/help             show this message
+/exit             leave the chat, Ctrl-D works too
+
+End a line with \\ or wrap the text in \"\"\" to enter multiple lines";
+
+/// Interactive chat on a session, every turn is saved to the session as it completes
+pub async fn chat(config: &Config<'_>, provider: &dyn Provider) -> Result<()> {
+    let http = config.http_client()?;
+    let mut store = config.open_history()?;
+    let name = store.resolve_name(config.session.as_deref())?;
+    let session = store.session(&name)?.unwrap_or_default();
+    let (mut model, mut system) = session_settings(config, &session);
+    let mut turns = session.chatlog;
+    let mut context = config.context;
+    // Files given with -f go along with the first prompt
+    let mut attachments = config.attachments.clone();
+
+    let mut editor = DefaultEditor::new()?;
+    let readline_history = config.history_path().with_extension("readline");
+    let _ = editor.load_history(&readline_history);
+    println!("Session '{name}' using {model}, /help lists the commands");
+
+    while let Some(input) = read_input(&mut editor)? {
+        if input.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(command) = input.strip_prefix('/') {
+            let (command, arg) = match command.split_once(char::is_whitespace) {
+                Some((command, arg)) => (command, arg.trim()),
+                None => (command.trim(), ""),
+            };
+            match command {
+                "model" if arg.is_empty() => println!("{model}"),
+                "model" => model = arg.to_string(),
+                "system" if arg.is_empty() => {
+                    println!("{}", system.as_deref().unwrap_or("(none)"))
+                }
+                "system" if arg == "-" => system = None,
+                "system" => system = Some(arg.to_string()),
+                "context" if arg.is_empty() => println!("{context}"),
+                "context" => match arg.parse() {
+                    Ok(value) => context = value,
+                    Err(_) => eprintln!("Error: /context takes a number"),
+                },
+                "clear" => {
+                    turns.clear();
+                    println!("Conversation cleared");
+                }
+                "save" => {
+                    store.set_settings(&name, Some(&model), system.as_deref())?;
+                    println!("Saved model and system prompt to session '{name}'");
+                }
+                "help" => println!("{HELP}"),
+                "exit" | "quit" => break,
+                _ => eprintln!("Unknown command /{command}, /help lists the commands"),
+            }
+            continue;
+        }
+
+        let input = input::compose(&input, &attachments);
+        attachments.clear();
+        let messages = build_messages(
+            system.as_deref(),
+            &turns,
+            context,
+            config.max_context_tokens,
+            &with_sources(config, &input),
+        );
+        let req = ChatRequest {
+            model: &model,
+            messages: &messages,
+            stream: config.stream,
+            temperature: config.temperature,
+            max_tokens: config.max_tokens,
+            tools: &[],
+            json_schema: None,
+        };
+        let answer = match respond(config, &http, provider, &req).await {
+            Ok(answer) => answer,
+            Err(err) => {
+                eprintln!("Error: {err}");
+                continue;
+            }
+        };
+
+        let entry = answer.into_entry(input, &model);
+        if !config.dont_save {
+            config.history_policy.save(store.as_mut(), &name, &entry)?;
+        }
+        turns.push(entry);
+    }
+
+    let _ = editor.save_history(&readline_history);
+    Ok(())
+}
+
+/// Reads one prompt, joining continuation lines. Returns `None` on Ctrl-D.
+fn read_input(editor: &mut DefaultEditor) -> Result<Option<String>> {
+    let mut lines: Vec<String> = vec![];
+    let mut in_block = false;
+    loop {
+        let prompt = if lines.is_empty() && !in_block {
+            ">>> "
+        } else {
+            "... "
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C throws away what was typed so far
+            Err(ReadlineError::Interrupted) => {
+                lines.clear();
+                in_block = false;
+                continue;
+            }
+            Err(ReadlineError::Eof) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        if in_block {
+            match line.trim_end().strip_suffix(BLOCK_DELIMITER) {
+                Some(last) => {
+                    if !last.is_empty() {
+                        lines.push(last.to_string());
+                    }
+                    break;
+                }
+                None => lines.push(line),
+            }
+        } else if let Some(first) = lines
+            .is_empty()
+            .then(|| line.trim_start().strip_prefix(BLOCK_DELIMITER))
+            .flatten()
+        {
+            if let Some(whole) = first.strip_suffix(BLOCK_DELIMITER) {
+                lines.push(whole.to_string());
+                break;
+            }
+            in_block = true;
+            if !first.is_empty() {
+                lines.push(first.to_string());
+            }
+        } else if let Some(line) = line.strip_suffix('\\') {
+            lines.push(line.to_string());
+        } else {
+            lines.push(line);
+            break;
+        }
+    }
+
+    let input = lines.join("\n");
+    if !input.trim().is_empty() {
+        let _ = editor.add_history_entry(input.as_str());
+    }
+    Ok(Some(input))
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Context, Result};
+use jsonschema::Validator;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// JSON schema the reply has to match, from `--json-schema`
+pub struct JsonSchema {
+    pub schema: Value,
+    validator: Validator,
+}
+
+impl JsonSchema {
+    pub fn load(path: &Path) -> Result<JsonSchema> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read JSON schema {}", path.display()))?;
+        let schema = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse JSON schema {}", path.display()))?;
+        JsonSchema::new(schema).with_context(|| format!("Invalid JSON schema {}", path.display()))
+    }
+
+    pub fn new(schema: Value) -> Result<JsonSchema> {
+        let validator = jsonschema::validator_for(&schema).map_err(|err| anyhow!("{err}"))?;
+        Ok(JsonSchema { schema, validator })
+    }
+
+    /// Added to the system prompt, for providers without a JSON mode
+    pub fn instruction(&self) -> String {
+        format!(
+            "Reply with only a JSON value, without any other text, matching this JSON schema:\n{}",
+            self.schema
+        )
+    }
+
+    /// Parses the reply and checks it against the schema. On a mismatch the error
+    /// lists every problem, worded to be sent back to the model.
+    pub fn validate(&self, reply: &str) -> Result<Value, String> {
+        let value: Value = serde_json::from_str(strip_code_fence(reply))
+            .map_err(|err| format!("the reply is not valid JSON: {err}"))?;
+        let problems: Vec<String> = self
+            .validator
+            .iter_errors(&value)
+            .map(|err| match err.instance_path.as_str() {
+                "" => err.to_string(),
+                path => format!("at {path}: {err}"),
+            })
+            .collect();
+        if problems.is_empty() {
+            Ok(value)
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+}
+
+/// Models often wrap JSON in a Markdown code block even when told not to
+fn strip_code_fence(reply: &str) -> &str {
+    let reply = reply.trim();
+    reply
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map(|inner| inner.split_once('\n').map_or(inner, |(_, body)| body))
+        .unwrap_or(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> JsonSchema {
+        JsonSchema::new(json!({
+            "type": "object",
+            "properties": {"name": {"type": "string"}, "age": {"type": "integer"}},
+            "required": ["name", "age"],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn valid_reply() {
+        let value = schema()
+            .validate("```json\n{\"name\": \"Ada\", \"age\": 36}\n```")
+            .unwrap();
+        assert_eq!(value, json!({"name": "Ada", "age": 36}));
+    }
+
+    #[test]
+    fn mismatches_are_described() {
+        let schema = schema();
+        assert!(schema
+            .validate("Sure! Here it is")
+            .unwrap_err()
+            .starts_with("the reply is not valid JSON"));
+        let problems = schema.validate(r#"{"name": 1}"#).unwrap_err();
+        assert!(problems.contains("at /name"), "{problems}");
+        assert!(
+            problems.contains("\"age\" is a required property"),
+            "{problems}"
+        );
+    }
+}
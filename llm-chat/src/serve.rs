@@ -0,0 +1,548 @@
+use anyhow::{bail, Context, Result};
+use axum::body::Body;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+use crate::api::{ApiError, HttpClient};
+use crate::cache::ResponseCache;
+use crate::history::{self, Entry};
+use crate::provider::{ChatMessage, ChatRequest, Provider, Reply, Usage};
+use crate::stream::EventDecoder;
+
+/// Session the exchanges are logged into when `--session` is not given
+pub const DEFAULT_SESSION: &str = "serve";
+
+/// Body of `POST /v1/chat/completions`, the fields the providers understand
+#[derive(Deserialize)]
+struct CompletionRequest {
+    #[serde(default)]
+    model: Option<String>,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    temperature: Option<f64>,
+    #[serde(default, alias = "max_completion_tokens")]
+    max_tokens: Option<u32>,
+    #[serde(default)]
+    tools: Vec<Value>,
+}
+
+/// Local OpenAI-compatible endpoint forwarding to the configured provider
+pub struct Server {
+    pub provider: Box<dyn Provider>,
+    pub http: HttpClient,
+    /// Model used when the request does not name one
+    pub default_model: String,
+    /// History store every exchange is logged into, `None` to log nothing. The
+    /// prompt of an entry is the last user message of the request, the earlier
+    /// messages are the client's context and are not logged again.
+    pub history: Option<PathBuf>,
+    pub history_policy: history::Policy,
+    pub session: String,
+    pub cache: Option<ResponseCache>,
+}
+
+/// Fields shared by every chunk of one completion
+struct Completion {
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl Completion {
+    fn new(model: &str) -> Completion {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Completion {
+            id: format!(
+                "chatcmpl-{created}-{}",
+                NEXT_ID.fetch_add(1, Ordering::Relaxed)
+            ),
+            created,
+            model: model.to_string(),
+        }
+    }
+
+    fn body(&self, reply: &Reply) -> Value {
+        let mut body = json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "message": reply.message,
+                "finish_reason": finish_reason(&reply.message),
+            }],
+        });
+        if let Some(usage) = reply.usage {
+            body["usage"] = usage_json(usage);
+        }
+        body
+    }
+
+    /// One server-sent event of a streamed completion
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>, usage: Option<Usage>) -> String {
+        let mut chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        });
+        if let Some(usage) = usage {
+            chunk["usage"] = usage_json(usage);
+        }
+        format!("data: {chunk}\n\n")
+    }
+
+    /// A whole reply sent to a client that asked for a stream
+    fn chunks(&self, reply: &Reply) -> Vec<String> {
+        let mut delta = json!({"role": "assistant", "content": reply.message.content});
+        if !reply.message.tool_calls.is_empty() {
+            let calls = reply.message.tool_calls.iter().enumerate();
+            delta["tool_calls"] = calls
+                .map(|(index, call)| {
+                    let mut call = json!(call);
+                    call["index"] = json!(index);
+                    call
+                })
+                .collect();
+        }
+        vec![
+            self.chunk(delta, None, None),
+            self.chunk(json!({}), Some(finish_reason(&reply.message)), reply.usage),
+            "data: [DONE]\n\n".to_string(),
+        ]
+    }
+}
+
+fn finish_reason(message: &ChatMessage) -> &'static str {
+    if message.tool_calls.is_empty() {
+        "stop"
+    } else {
+        "tool_calls"
+    }
+}
+
+fn usage_json(usage: Usage) -> Value {
+    json!({
+        "prompt_tokens": usage.prompt_tokens,
+        "completion_tokens": usage.completion_tokens,
+        "total_tokens": usage.prompt_tokens + usage.completion_tokens,
+    })
+}
+
+/// An error in the OpenAI format
+fn error_response(status: StatusCode, message: &str) -> Response {
+    let body = json!({"error": {"message": message, "type": "llm_chat_error"}});
+    (status, Json(body)).into_response()
+}
+
+fn event_stream(events: mpsc::Receiver<String>) -> Response {
+    let body = stream::unfold(events, |mut events| async move {
+        events
+            .recv()
+            .await
+            .map(|event| (Ok::<_, Infallible>(event), events))
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "text/event-stream"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+impl Server {
+    async fn complete(self: &Arc<Self>, request: CompletionRequest) -> Result<Response> {
+        let model = request
+            .model
+            .clone()
+            .filter(|model| !model.is_empty())
+            .unwrap_or_else(|| self.default_model.clone());
+        // Tool calls are only parsed from whole replies, they are sent as one chunk
+        let req = ChatRequest {
+            model: &model,
+            messages: &request.messages,
+            stream: request.stream && request.tools.is_empty(),
+            temperature: request.temperature,
+            max_tokens: request.max_tokens,
+            tools: &request.tools,
+            json_schema: None,
+        };
+        let prompt = request
+            .messages
+            .iter()
+            .rev()
+            .find(|message| message.role == "user")
+            .map(|message| message.content.clone())
+            .unwrap_or_default();
+        let key = ResponseCache::key(self.provider.base_url(), &req);
+        let completion = Completion::new(&model);
+
+        let cached = self.cache.as_ref().and_then(|cache| cache.get(&key));
+        let reply = match cached {
+            Some(reply) => {
+                // Nothing was billed for a cached reply
+                self.log(
+                    &prompt,
+                    &model,
+                    &reply.message,
+                    Some(Usage::default()),
+                    true,
+                );
+                reply
+            }
+            None if req.stream => {
+                let response = self
+                    .http
+                    .send(self.provider.build_request(&self.http.client, &req))
+                    .await?;
+                let (events, receiver) = mpsc::channel(32);
+                let server = Arc::clone(self);
+                tokio::spawn(async move {
+                    server
+                        .relay(response, completion, events, prompt, key)
+                        .await
+                });
+                return Ok(event_stream(receiver));
+            }
+            None => {
+                let reply = crate::request_reply(&self.http, self.provider.as_ref(), &req).await?;
+                self.store(&key, &reply);
+                self.log(&prompt, &model, &reply.message, reply.usage, false);
+                reply
+            }
+        };
+
+        if request.stream {
+            let (events, receiver) = mpsc::channel(4);
+            for chunk in completion.chunks(&reply) {
+                let _ = events.send(chunk).await;
+            }
+            Ok(event_stream(receiver))
+        } else {
+            Ok(Json(completion.body(&reply)).into_response())
+        }
+    }
+
+    /// Passes the deltas of a streamed response on to the client, then logs and
+    /// caches the reply. A client that goes away ends the relay early.
+    async fn relay(
+        &self,
+        response: reqwest::Response,
+        completion: Completion,
+        events: mpsc::Sender<String>,
+        prompt: String,
+        key: String,
+    ) {
+        let mut text = String::new();
+        let mut usage = None;
+        let result = self
+            .read_stream(response, &completion, &events, &mut text, &mut usage)
+            .await;
+        let reply = Reply {
+            message: ChatMessage::new("assistant", text),
+            usage,
+        };
+        match result {
+            Ok(()) => {
+                for chunk in completion.chunks(&reply).into_iter().skip(1) {
+                    let _ = events.send(chunk).await;
+                }
+                self.store(&key, &reply);
+            }
+            Err(err) => eprintln!("Stream for {} ended early: {err}", completion.id),
+        }
+        if !reply.message.content.is_empty() {
+            self.log(&prompt, &completion.model, &reply.message, usage, false);
+        }
+    }
+
+    async fn read_stream(
+        &self,
+        response: reqwest::Response,
+        completion: &Completion,
+        events: &mpsc::Sender<String>,
+        text: &mut String,
+        usage: &mut Option<Usage>,
+    ) -> Result<()> {
+        let mut body = response.bytes_stream();
+        let mut decoder = EventDecoder::new(self.provider.stream_format());
+        loop {
+            let (payloads, done) = match body.next().await {
+                Some(chunk) => (decoder.push(&chunk.map_err(ApiError::from)?), false),
+                None => (decoder.finish().into_iter().collect(), true),
+            };
+            for data in payloads {
+                let delta = self
+                    .provider
+                    .parse_stream_event(&data)
+                    .map_err(|err| ApiError::Malformed(err.to_string()))?;
+                if let Some(delta) = delta.text {
+                    let chunk = completion.chunk(json!({"content": delta}), None, None);
+                    if events.send(chunk).await.is_err() {
+                        bail!("the client disconnected");
+                    }
+                    text.push_str(&delta);
+                }
+                *usage = Usage::combine(*usage, delta.usage);
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    fn store(&self, key: &str, reply: &Reply) {
+        if let Some(cache) = &self.cache {
+            if let Err(err) = cache.put(key, reply) {
+                eprintln!("Failed to cache the reply: {err}");
+            }
+        }
+    }
+
+    /// Prints a line about the exchange and appends it to the history in the
+    /// background
+    fn log(
+        &self,
+        prompt: &str,
+        model: &str,
+        reply: &ChatMessage,
+        usage: Option<Usage>,
+        cached: bool,
+    ) {
+        let mut entry =
+            Entry::new(prompt.to_string(), reply.content.clone(), model).with_usage(usage);
+        if !reply.tool_calls.is_empty() {
+            entry.tool_log = vec![reply.clone()];
+        }
+        entry.cached = cached;
+        eprintln!(
+            "{model}: {} prompt, {} completion tokens{}",
+            entry.prompt_tokens.unwrap_or(0),
+            entry.response_tokens.unwrap_or(0),
+            if cached { " (cached)" } else { "" }
+        );
+        let Some(path) = self.history.clone() else {
+            return;
+        };
+        let policy = self.history_policy.clone();
+        let session = self.session.clone();
+        // A locked database is waited for, so the write is kept off the async
+        // workers. Writes take turns so the JSON store is never written twice at once.
+        static WRITING: Mutex<()> = Mutex::new(());
+        tokio::task::spawn_blocking(move || {
+            let _turn = WRITING.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(err) = history::open(&path)
+                .and_then(|mut store| policy.save(store.as_mut(), &session, &entry))
+            {
+                eprintln!("Failed to log the exchange: {err}");
+            }
+        });
+    }
+}
+
+async fn completions(
+    State(server): State<Arc<Server>>,
+    body: Result<Json<CompletionRequest>, JsonRejection>,
+) -> Response {
+    let request = match body {
+        Ok(Json(request)) => request,
+        Err(rejection) => return error_response(StatusCode::BAD_REQUEST, &rejection.body_text()),
+    };
+    // Other providers would drop the tools and answer as if none were given
+    if !request.tools.is_empty() && !server.provider.supports_tools() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "The configured provider does not support tools",
+        );
+    }
+    match server.complete(request).await {
+        Ok(response) => response,
+        Err(err) => {
+            let status = match err.downcast_ref::<ApiError>() {
+                Some(ApiError::Status { status, .. }) => *status,
+                Some(ApiError::RateLimited { .. }) => StatusCode::TOO_MANY_REQUESTS,
+                Some(ApiError::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::BAD_GATEWAY,
+            };
+            eprintln!("{err}");
+            error_response(status, &err.to_string())
+        }
+    }
+}
+
+async fn models(State(server): State<Arc<Server>>) -> Json<Value> {
+    Json(json!({
+        "object": "list",
+        "data": [{"id": server.default_model, "object": "model", "owned_by": "llm_chat"}],
+    }))
+}
+
+pub fn router(server: Server) -> Router {
+    Router::new()
+        .route("/v1/chat/completions", post(completions))
+        .route("/v1/models", get(models))
+        .with_state(Arc::new(server))
+}
+
+/// Serves until Ctrl-C
+pub async fn run(server: Server, addr: SocketAddr) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {addr}"))?;
+    eprintln!(
+        "Serving {} on http://{}/v1",
+        server.provider.base_url(),
+        listener.local_addr()?
+    );
+    axum::serve(listener, router(server))
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::RetryPolicy;
+    use crate::provider::{self, ProviderKind};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn replies_are_forwarded_and_cached() {
+        let mut upstream = mockito::Server::new_async().await;
+        let mock = upstream
+            .mock("POST", "/chat/completions")
+            .with_body(
+                r#"{"choices":[{"message":{"role":"assistant","content":"hello"}}],
+                    "usage":{"prompt_tokens":4,"completion_tokens":1}}"#,
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let dir = std::env::temp_dir().join(format!("llm_chat_serve_{}", std::process::id()));
+        let history_path = dir.join("history.json");
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = Arc::new(Server {
+            provider: provider::build_provider(ProviderKind::Openai, Some(upstream.url()), None),
+            http: HttpClient::new(None, Duration::from_secs(5), RetryPolicy::default()).unwrap(),
+            default_model: "m".to_string(),
+            history: Some(history_path.clone()),
+            history_policy: history::Policy::default(),
+            session: DEFAULT_SESSION.to_string(),
+            cache: Some(ResponseCache::new(dir.clone(), crate::cache::DEFAULT_TTL)),
+        });
+        let request = || CompletionRequest {
+            model: None,
+            messages: vec![
+                ChatMessage::new("user", "hello?"),
+                ChatMessage::new("assistant", "yes"),
+                ChatMessage::new("user", "hi"),
+            ],
+            stream: false,
+            temperature: Some(0.0),
+            max_tokens: None,
+            tools: vec![],
+        };
+
+        let response = server.complete(request()).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "hello");
+        assert_eq!(body["usage"]["total_tokens"], 5);
+
+        let streamed = CompletionRequest {
+            stream: true,
+            ..request()
+        };
+        let response = server.complete(streamed).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let events = String::from_utf8(body.to_vec()).unwrap();
+        assert!(events.contains(r#""delta":{"content":"hello","role":"assistant"}"#));
+        assert!(events.ends_with("data: [DONE]\n\n"));
+        mock.assert_async().await;
+
+        // Exchanges are logged in the background
+        let mut chatlog = vec![];
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let store = history::open(&history_path).unwrap();
+            chatlog = store
+                .session(DEFAULT_SESSION)
+                .unwrap()
+                .unwrap_or_default()
+                .chatlog;
+            if chatlog.len() == 2 {
+                break;
+            }
+        }
+        let prompts: Vec<&str> = chatlog.iter().map(|entry| entry.prompt.as_str()).collect();
+        assert_eq!(prompts, ["hi", "hi"]);
+        let cached: Vec<bool> = chatlog.iter().map(|entry| entry.cached).collect();
+        assert_eq!(cached, [false, true]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tools_need_provider_support() {
+        let mut upstream = mockito::Server::new_async().await;
+        let mock = upstream
+            .mock("POST", "/messages")
+            .expect(0)
+            .create_async()
+            .await;
+        let server = Arc::new(Server {
+            provider: provider::build_provider(ProviderKind::Anthropic, Some(upstream.url()), None),
+            http: HttpClient::new(None, Duration::from_secs(5), RetryPolicy::default()).unwrap(),
+            default_model: "m".to_string(),
+            history: None,
+            history_policy: history::Policy::default(),
+            session: DEFAULT_SESSION.to_string(),
+            cache: None,
+        });
+        let request = CompletionRequest {
+            model: None,
+            messages: vec![ChatMessage::new("user", "list the files")],
+            stream: true,
+            temperature: None,
+            max_tokens: None,
+            tools: vec![json!({"type": "function", "function": {"name": "list_dir"}})],
+        };
+
+        let response = completions(State(server), Ok(Json(request))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("does not support tools"));
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,77 @@
+/// How a provider frames the events of a streamed response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFormat {
+    /// Server-sent events, the payload is on the `data:` lines
+    Sse,
+    /// One JSON object per line
+    JsonLines,
+}
+
+/// Buffers the raw response body and splits it into event payloads
+pub struct EventDecoder {
+    format: StreamFormat,
+    buf: Vec<u8>,
+}
+
+impl EventDecoder {
+    pub fn new(format: StreamFormat) -> Self {
+        EventDecoder {
+            format,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Feeds a chunk of the body and returns the payloads of every completed line
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(chunk);
+        let mut payloads = vec![];
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(payload) = self.payload(line.trim_end_matches(['\r', '\n'])) {
+                payloads.push(payload);
+            }
+        }
+        payloads
+    }
+
+    /// Returns whatever is left in the buffer once the body has ended
+    pub fn finish(&mut self) -> Option<String> {
+        let line = String::from_utf8_lossy(&self.buf).trim().to_string();
+        self.buf.clear();
+        self.payload(&line)
+    }
+
+    fn payload(&self, line: &str) -> Option<String> {
+        match self.format {
+            StreamFormat::Sse => {
+                let data = line.strip_prefix("data:")?.trim_start();
+                (data != "[DONE]").then(|| data.to_string())
+            }
+            StreamFormat::JsonLines => (!line.trim().is_empty()).then(|| line.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_split_across_chunks() {
+        let mut decoder = EventDecoder::new(StreamFormat::Sse);
+        assert!(decoder.push(b"event: delta\r\ndata: {\"a\"").is_empty());
+        assert_eq!(
+            decoder.push(b":1}\r\n\r\n: ping\ndata: [DONE]\n"),
+            vec!["{\"a\":1}"]
+        );
+        assert_eq!(decoder.finish(), None);
+    }
+
+    #[test]
+    fn json_lines_without_trailing_newline() {
+        let mut decoder = EventDecoder::new(StreamFormat::JsonLines);
+        assert_eq!(decoder.push(b"{}\n\n{\"b\""), vec!["{}"]);
+        assert_eq!(decoder.finish().as_deref(), Some("{\"b\""));
+    }
+}
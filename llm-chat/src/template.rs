@@ -0,0 +1,250 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory of the templates, `templates` next to the config file
+pub fn templates_dir(config_path: &Path) -> PathBuf {
+    config_path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join("templates")
+}
+
+/// A `{{...}}` placeholder in a template
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Placeholder {
+    /// `{{input}}`, the prompt arguments with piped stdin and attached files
+    Input,
+    /// `{{file:path}}`, the content of a file
+    File(String),
+    /// `{{env:VAR}}`, an environment variable
+    Env(String),
+    /// `{{name}}`, a value given with `--var name=value`
+    Var(String),
+}
+
+impl Placeholder {
+    fn parse(inner: &str) -> Placeholder {
+        let inner = inner.trim();
+        if inner == "input" {
+            Placeholder::Input
+        } else if let Some(path) = inner.strip_prefix("file:") {
+            Placeholder::File(path.trim().to_string())
+        } else if let Some(var) = inner.strip_prefix("env:") {
+            Placeholder::Env(var.trim().to_string())
+        } else {
+            Placeholder::Var(inner.to_string())
+        }
+    }
+}
+
+impl std::fmt::Display for Placeholder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Placeholder::Input => write!(f, "{{{{input}}}}"),
+            Placeholder::File(path) => write!(f, "{{{{file:{path}}}}}"),
+            Placeholder::Env(var) => write!(f, "{{{{env:{var}}}}}"),
+            Placeholder::Var(name) => write!(f, "{{{{{name}}}}}"),
+        }
+    }
+}
+
+/// Text between placeholders, or a placeholder
+enum Part<'a> {
+    Text(&'a str),
+    Placeholder(Placeholder),
+}
+
+/// A prompt stored as a file in the templates directory, named after the file
+/// without its extension
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    text: String,
+}
+
+impl Template {
+    pub fn new(name: &str, text: &str) -> Template {
+        Template {
+            name: name.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    pub fn load(dir: &Path, name: &str) -> Result<Template> {
+        list(dir)?
+            .into_iter()
+            .find(|template| template.name == name)
+            .with_context(|| {
+                format!(
+                    "No template named '{name}' in {}, see `llm_chat templates`",
+                    dir.display()
+                )
+            })
+    }
+
+    fn parts(&self) -> Vec<Part<'_>> {
+        let mut parts = vec![];
+        let mut rest = self.text.as_str();
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else {
+                break;
+            };
+            parts.push(Part::Text(&rest[..start]));
+            parts.push(Part::Placeholder(Placeholder::parse(
+                &rest[start + 2..start + 2 + len],
+            )));
+            rest = &rest[start + 2 + len + 2..];
+        }
+        parts.push(Part::Text(rest));
+        parts
+    }
+
+    /// Every distinct placeholder, sorted
+    pub fn placeholders(&self) -> Vec<Placeholder> {
+        let mut placeholders: Vec<Placeholder> = self
+            .parts()
+            .into_iter()
+            .filter_map(|part| match part {
+                Part::Placeholder(placeholder) => Some(placeholder),
+                Part::Text(_) => None,
+            })
+            .collect();
+        placeholders.sort();
+        placeholders.dedup();
+        placeholders
+    }
+
+    /// Fills in the placeholders, failing with a list of every one that has no value.
+    /// Input for a template without `{{input}}` is appended to it.
+    pub fn render(&self, input: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+        let mut out = String::new();
+        let mut missing = vec![];
+        for part in self.parts() {
+            let value = match part {
+                Part::Text(text) => Some(text.to_string()),
+                Part::Placeholder(Placeholder::Input) if input.is_empty() => {
+                    missing.push("{{input}} (give a prompt or pipe text)".to_string());
+                    None
+                }
+                Part::Placeholder(Placeholder::Input) => Some(input.to_string()),
+                Part::Placeholder(Placeholder::File(path)) => match fs::read_to_string(&path) {
+                    Ok(content) => Some(content.trim_end_matches('\n').to_string()),
+                    Err(err) => {
+                        missing.push(format!("{{{{file:{path}}}}} ({err})"));
+                        None
+                    }
+                },
+                Part::Placeholder(Placeholder::Env(var)) => match env::var(&var) {
+                    Ok(value) => Some(value),
+                    Err(_) => {
+                        missing.push(format!("{{{{env:{var}}}}} (not set)"));
+                        None
+                    }
+                },
+                Part::Placeholder(Placeholder::Var(name)) => match vars.get(&name) {
+                    Some(value) => Some(value.clone()),
+                    None => {
+                        missing.push(format!("{{{{{name}}}}} (pass --var {name}=VALUE)"));
+                        None
+                    }
+                },
+            };
+            out.push_str(&value.unwrap_or_default());
+        }
+        if !missing.is_empty() {
+            missing.sort();
+            missing.dedup();
+            bail!(
+                "Template '{}' is missing values for {}",
+                self.name,
+                missing.join(", ")
+            );
+        }
+        if !input.is_empty() && !self.placeholders().contains(&Placeholder::Input) {
+            out = format!("{}\n\n{input}", out.trim_end());
+        }
+        Ok(out.trim().to_string())
+    }
+}
+
+/// Templates in the directory sorted by name, a missing directory has none
+pub fn list(dir: &Path) -> Result<Vec<Template>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut templates = vec![];
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read templates directory {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if !path.is_file() || name.starts_with('.') {
+            continue;
+        }
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read template {}", path.display()))?;
+        templates.push(Template::new(name, &text));
+    }
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(templates)
+}
+
+/// Parses a `--var name=value` argument
+pub fn parse_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=VALUE, got '{arg}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_filled() {
+        let template = Template::new(
+            "review",
+            "Review for {{ lang }} in {{env:LLM_CHAT_TEST_HOME}}:\n{{input}}\n{{unclosed",
+        );
+        env::set_var("LLM_CHAT_TEST_HOME", "/home");
+        let vars = BTreeMap::from([("lang".to_string(), "rust".to_string())]);
+        assert_eq!(
+            template.render("diff", &vars).unwrap(),
+            "Review for rust in /home:\ndiff\n{{unclosed"
+        );
+        assert_eq!(
+            Template::new("commit", "Write a commit message")
+                .render("diff", &vars)
+                .unwrap(),
+            "Write a commit message\n\ndiff"
+        );
+    }
+
+    #[test]
+    fn missing_values_are_listed() {
+        let template = Template::new("t", "{{input}} {{file:/no/such/file}} {{name}} {{input}}");
+        assert_eq!(
+            template.placeholders(),
+            [
+                Placeholder::Input,
+                Placeholder::File("/no/such/file".to_string()),
+                Placeholder::Var("name".to_string()),
+            ]
+        );
+        let err = template
+            .render("", &BTreeMap::new())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("{{input}} (give a prompt"), "{err}");
+        assert!(err.contains("{{file:/no/such/file}} ("), "{err}");
+        assert!(err.contains("--var name=VALUE"), "{err}");
+    }
+}
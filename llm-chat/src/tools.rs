@@ -0,0 +1,303 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Longest tool output sent back to the model
+const MAX_OUTPUT_BYTES: usize = 32 * 1024;
+
+/// Local tools the model may call, from the `[tools]` table of the config file:
+///
+/// ```toml
+/// [tools]
+/// enabled = ["read_file", "list_dir", "run_command"]
+/// allowed_commands = ["git", "cargo"]
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ToolsConfig {
+    #[serde(default)]
+    pub enabled: Vec<String>,
+    /// Programs `run_command` is allowed to execute
+    #[serde(default)]
+    pub allowed_commands: Vec<String>,
+    /// Most tool calling rounds before giving up on a final answer
+    #[serde(default = "default_max_rounds")]
+    pub max_rounds: usize,
+}
+
+fn default_max_rounds() -> usize {
+    8
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        ToolsConfig {
+            enabled: vec![],
+            allowed_commands: vec![],
+            max_rounds: default_max_rounds(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments
+    pub arguments: String,
+}
+
+#[derive(Deserialize)]
+struct PathArgs {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct CommandArgs {
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+impl ToolsConfig {
+    pub fn is_empty(&self) -> bool {
+        self.enabled.is_empty()
+    }
+
+    /// Tool definitions in the OpenAI `tools` format
+    pub fn definitions(&self) -> Result<Vec<Value>> {
+        self.enabled
+            .iter()
+            .map(|name| {
+                let (description, parameters) = match name.as_str() {
+                    "read_file" => (
+                        "Read a UTF-8 text file in the working directory",
+                        json!({
+                            "type": "object",
+                            "properties": {"path": {"type": "string"}},
+                            "required": ["path"],
+                        }),
+                    ),
+                    "list_dir" => (
+                        "List the entries of a directory in the working directory",
+                        json!({
+                            "type": "object",
+                            "properties": {"path": {"type": "string"}},
+                            "required": ["path"],
+                        }),
+                    ),
+                    "run_command" => (
+                        "Run a program without a shell and return its output",
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "program": {"type": "string", "enum": self.allowed_commands},
+                                "args": {"type": "array", "items": {"type": "string"}},
+                            },
+                            "required": ["program"],
+                        }),
+                    ),
+                    other => bail!("Unknown tool '{other}' in the config file"),
+                };
+                Ok(json!({
+                    "type": "function",
+                    "function": {
+                        "name": name,
+                        "description": description,
+                        "parameters": parameters,
+                    },
+                }))
+            })
+            .collect()
+    }
+
+    /// Runs the tool and returns its output
+    pub fn execute(&self, call: &FunctionCall) -> Result<String> {
+        if !self.enabled.contains(&call.name) {
+            bail!("Tool '{}' is not enabled", call.name);
+        }
+        let output = match call.name.as_str() {
+            "read_file" => {
+                let args: PathArgs = serde_json::from_str(&call.arguments)?;
+                fs::read_to_string(inside_working_dir(&args.path)?)
+                    .with_context(|| format!("Failed to read {}", args.path))?
+            }
+            "list_dir" => {
+                let args: PathArgs = serde_json::from_str(&call.arguments)?;
+                let mut names = fs::read_dir(inside_working_dir(&args.path)?)
+                    .with_context(|| format!("Failed to list {}", args.path))?
+                    .map(|entry| {
+                        let entry = entry?;
+                        let mut name = entry.file_name().to_string_lossy().to_string();
+                        if entry.file_type()?.is_dir() {
+                            name.push('/');
+                        }
+                        Ok(name)
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                names.sort();
+                names.join("\n")
+            }
+            "run_command" => {
+                let args: CommandArgs = serde_json::from_str(&call.arguments)?;
+                if !self.allowed_commands.contains(&args.program) {
+                    bail!("'{}' is not in allowed_commands", args.program);
+                }
+                let output = Command::new(&args.program)
+                    .args(&args.args)
+                    .output()
+                    .with_context(|| format!("Failed to run {}", args.program))?;
+                format!(
+                    "exit status: {}\n{}{}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stdout),
+                    String::from_utf8_lossy(&output.stderr)
+                )
+            }
+            other => bail!("Unknown tool '{other}'"),
+        };
+        Ok(truncate(output))
+    }
+}
+
+fn truncate(mut output: String) -> String {
+    if output.len() > MAX_OUTPUT_BYTES {
+        let mut end = MAX_OUTPUT_BYTES;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output.push_str("\n[output truncated]");
+    }
+    output
+}
+
+/// The path with symlinks resolved, if it is inside the working directory.
+/// The file tools do not read anything outside of it.
+fn inside_working_dir(path: &str) -> Result<PathBuf> {
+    let dir = env::current_dir()?.canonicalize()?;
+    let full = dir
+        .join(path)
+        .canonicalize()
+        .with_context(|| format!("Failed to find {path}"))?;
+    if !full.starts_with(&dir) {
+        bail!("{path} is outside the working directory");
+    }
+    Ok(full)
+}
+
+fn open_terminal() -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).open("/dev/tty")
+}
+
+/// Whether there is a terminal to confirm tool calls on, even with stdin piped
+pub fn can_confirm() -> bool {
+    open_terminal().is_ok()
+}
+
+/// Asks on the terminal whether the tool may run, refusing when there is no terminal
+pub fn confirm_on_terminal(call: &FunctionCall) -> bool {
+    let Ok(mut terminal) = open_terminal() else {
+        eprintln!("Not running {} without a terminal to confirm it", call.name);
+        return false;
+    };
+    if write!(terminal, "Run {} {}? [y/N] ", call.name, call.arguments).is_err() {
+        return false;
+    }
+    let mut answer = String::new();
+    if BufReader::new(terminal).read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tools() -> ToolsConfig {
+        ToolsConfig {
+            enabled: vec!["list_dir".to_string(), "run_command".to_string()],
+            allowed_commands: vec!["echo".to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn call(name: &str, arguments: Value) -> FunctionCall {
+        FunctionCall {
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        }
+    }
+
+    #[test]
+    fn only_enabled_and_allowed() {
+        let tools = tools();
+        assert!(tools
+            .execute(&call("read_file", json!({"path": "Cargo.toml"})))
+            .is_err());
+        assert!(tools
+            .execute(&call(
+                "run_command",
+                json!({"program": "rm", "args": ["-rf", "x"]})
+            ))
+            .is_err());
+        let output = tools
+            .execute(&call(
+                "run_command",
+                json!({"program": "echo", "args": ["hi"]}),
+            ))
+            .unwrap();
+        assert!(output.ends_with("hi\n"));
+        assert!(tools
+            .execute(&call("list_dir", json!({"path": "."})))
+            .unwrap()
+            .contains("src/"));
+    }
+
+    #[test]
+    fn files_outside_the_working_directory() {
+        let tools = ToolsConfig {
+            enabled: vec!["read_file".to_string(), "list_dir".to_string()],
+            ..Default::default()
+        };
+        assert!(tools
+            .execute(&call("read_file", json!({"path": "src/../Cargo.toml"})))
+            .unwrap()
+            .contains("[package]"));
+        for (tool, path) in [
+            ("list_dir", ".."),
+            ("list_dir", "/"),
+            ("read_file", "src/../.."),
+        ] {
+            let err = tools
+                .execute(&call(tool, json!({ "path": path })))
+                .unwrap_err();
+            assert!(err.to_string().contains("outside the working directory"));
+        }
+    }
+
+    #[test]
+    fn unknown_tool_in_config() {
+        let mut tools = tools();
+        assert_eq!(tools.definitions().unwrap().len(), 2);
+        tools.enabled.push("format_disk".to_string());
+        assert!(tools.definitions().is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::Result;
+use chrono::NaiveDate;
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+
+use crate::config_file::Price;
+use crate::history::{Entry, HistoryStore};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupBy {
+    Day,
+    Model,
+    Session,
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct Totals {
+    pub requests: usize,
+    /// Requests answered from the cache, they cost nothing
+    pub cached: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Dollars spent on the models that have a price
+    pub cost: f64,
+    /// Requests to models without a price, not included in `cost`
+    pub unpriced: usize,
+}
+
+impl Totals {
+    fn add(&mut self, entry: &Entry, price: Option<&Price>) {
+        let prompt_tokens = entry.prompt_tokens.unwrap_or(0);
+        let completion_tokens = entry.response_tokens.unwrap_or(0);
+        self.requests += 1;
+        self.cached += entry.cached as usize;
+        self.prompt_tokens += prompt_tokens;
+        self.completion_tokens += completion_tokens;
+        match price {
+            Some(price) => self.cost += price.cost(prompt_tokens, completion_tokens),
+            None => self.unpriced += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &Totals) {
+        self.requests += other.requests;
+        self.cached += other.cached;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+        self.unpriced += other.unpriced;
+    }
+}
+
+/// Token counts and cost of every entry, grouped by local day, model or session.
+/// With `since` only entries from that day on are counted.
+pub fn totals(
+    store: &dyn HistoryStore,
+    prices: &BTreeMap<String, Price>,
+    by: GroupBy,
+    since: Option<NaiveDate>,
+) -> Result<BTreeMap<String, Totals>> {
+    let mut groups: BTreeMap<String, Totals> = BTreeMap::new();
+    for summary in store.list()? {
+        let Some(session) = store.session(&summary.name)? else {
+            continue;
+        };
+        for entry in &session.chatlog {
+            let day = entry.local_day();
+            if since.is_some_and(|since| day.is_none_or(|day| day < since)) {
+                continue;
+            }
+            let model = entry.model.as_deref().unwrap_or("unknown");
+            let key = match by {
+                GroupBy::Day => day.map_or("unknown".to_string(), |day| day.to_string()),
+                GroupBy::Model => model.to_string(),
+                GroupBy::Session => summary.name.clone(),
+            };
+            groups.entry(key).or_default().add(entry, prices.get(model));
+        }
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::SqliteStore;
+
+    fn entry(model: &str, timestamp: i64, tokens: usize) -> Entry {
+        Entry {
+            timestamp: Some(timestamp),
+            prompt_tokens: Some(tokens),
+            response_tokens: Some(tokens),
+            ..Entry::new(String::new(), String::new(), model)
+        }
+    }
+
+    #[test]
+    fn grouped_totals() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let day = 86_400;
+        store.append("a", &entry("cheap", 10 * day, 1000)).unwrap();
+        let cached = Entry {
+            cached: true,
+            ..entry("free", 10 * day, 0)
+        };
+        store.append("a", &entry("free", 10 * day, 500)).unwrap();
+        store.append("a", &cached).unwrap();
+        store.append("b", &entry("cheap", 20 * day, 2000)).unwrap();
+        let prices = BTreeMap::from([(
+            "cheap".to_string(),
+            Price {
+                prompt: 1.0,
+                completion: 2.0,
+            },
+        )]);
+
+        let by_model = totals(&store, &prices, GroupBy::Model, None).unwrap();
+        assert_eq!(by_model["cheap"].requests, 2);
+        assert_eq!(by_model["cheap"].prompt_tokens, 3000);
+        assert!((by_model["cheap"].cost - 0.009).abs() < 1e-9);
+        assert_eq!(by_model["free"].unpriced, 2);
+        assert_eq!(by_model["free"].cached, 1);
+
+        let by_session = totals(&store, &prices, GroupBy::Session, None).unwrap();
+        assert_eq!(by_session["a"].requests, 3);
+
+        let since = entry("x", 15 * day, 0).local_day();
+        let by_day = totals(&store, &prices, GroupBy::Day, since).unwrap();
+        assert_eq!(by_day.len(), 1);
+        assert_eq!(by_day.values().next().unwrap().completion_tokens, 2000);
+    }
+}
@@ -1,25 +1,69 @@
+use rand::{Rng, RngCore};
+
 use crate::{ray::Ray, Vec3};
 
+fn random_in_unit_disk(rng: &mut dyn RngCore) -> Vec3 {
+    loop {
+        let p = 2.0 * Vec3::new(rng.random::<f64>(), rng.random::<f64>(), 0.0)
+            - Vec3::new(1.0, 1.0, 0.0);
+        if p.dot(&p) < 1.0 {
+            break p;
+        }
+    }
+}
+
 pub struct Camera {
     origin: Vec3,
     lower_left_corner: Vec3,
     horizontal: Vec3,
     vertical: Vec3,
+    u: Vec3,
+    v: Vec3,
+    lens_radius: f64,
 }
 
 impl Camera {
-    pub fn new() -> Self {
+    /// Camera at `look_from` facing `look_at`, rolled so `vup` points up in the image.
+    /// `vfov` is the vertical field of view in degrees and `aspect` the image width
+    /// over its height. Objects `focus_dist` away are sharp, the wider the
+    /// `aperture` the blurrier everything else is.
+    pub fn new(
+        look_from: Vec3,
+        look_at: Vec3,
+        vup: Vec3,
+        vfov: f64,
+        aspect: f64,
+        aperture: f64,
+        focus_dist: f64,
+    ) -> Self {
+        let theta = vfov.to_radians();
+        let half_height = (theta / 2.0).tan();
+        let half_width = aspect * half_height;
+        let w = (look_from - look_at).unit_vec();
+        let u = vup.cross(&w).unit_vec();
+        let v = w.cross(&u);
         Self {
-            origin: Vec3::new(0.0, 0.0, 0.0),
-            lower_left_corner: Vec3::new(-2.0, -1.0, -1.0),
-            horizontal: Vec3::new(4.0, 0.0, 0.0),
-            vertical: Vec3::new(0.0, 2.0, 0.0),
+            origin: look_from,
+            lower_left_corner: look_from
+                - half_width * focus_dist * u
+                - half_height * focus_dist * v
+                - focus_dist * w,
+            horizontal: 2.0 * half_width * focus_dist * u,
+            vertical: 2.0 * half_height * focus_dist * v,
+            u,
+            v,
+            lens_radius: aperture / 2.0,
         }
     }
-    pub fn get_ray(&self, u: f64, v: f64) -> Ray {
+    /// Ray through `s` across and `t` up the image, the lens sampled from `rng`
+    pub fn get_ray_with(&self, s: f64, t: f64, rng: &mut dyn RngCore) -> Ray {
+        let rd = self.lens_radius * random_in_unit_disk(rng);
+        let offset = self.u * rd.x() + self.v * rd.y();
         Ray::new(
-            self.origin,
-            self.lower_left_corner + (u * self.horizontal) + (v * self.vertical),
+            self.origin + offset,
+            self.lower_left_corner + (s * self.horizontal) + (t * self.vertical)
+                - self.origin
+                - offset,
         )
     }
 }
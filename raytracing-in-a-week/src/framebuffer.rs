@@ -0,0 +1,188 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::{error::Error, path::Path};
+
+use crate::Vec3;
+
+/// Linear colors of an image, row by row from the top left corner.
+/// `gamma` is applied when writing 8 bit formats.
+#[derive(Clone, PartialEq)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    gamma: f64,
+    pixels: Vec<Vec3>,
+}
+
+impl Framebuffer {
+    /// Black image
+    pub fn new(width: u32, height: u32, gamma: f64) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            gamma,
+            pixels: vec![Vec3::new(0.0, 0.0, 0.0); width as usize * height as usize],
+        }
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+
+    /// Color `x` from the left and `y` from the top
+    pub fn get(&self, x: u32, y: u32) -> Vec3 {
+        self.pixels[self.index(x, y)]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, color: Vec3) {
+        let index = self.index(x, y);
+        self.pixels[index] = color;
+    }
+
+    /// Gamma corrected 8 bit red, green and blue of every pixel
+    fn rgb8(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * 3);
+        for col in &self.pixels {
+            let col = col.powf(1.0 / self.gamma);
+            for i in 0..3 {
+                bytes.push((255.99 * col[i].clamp(0.0, 1.0)) as u8);
+            }
+        }
+        bytes
+    }
+
+    /// Binary PPM
+    pub fn write_p6(&self, mut out: impl Write) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.rgb8())
+    }
+
+    pub fn write_png(&self, out: impl Write) -> Result<(), Box<dyn Error>> {
+        let mut encoder = png::Encoder::new(out, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.rgb8())?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Portable float map of the linear colors, for tone mapping elsewhere
+    pub fn write_pfm(&self, mut out: impl Write) -> io::Result<()> {
+        // A negative scale means little endian floats, rows go from the bottom up
+        write!(out, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let col = self.get(x, y);
+                for i in 0..3 {
+                    out.write_all(&(col[i] as f32).to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes PNG, PPM or PFM depending on the extension of `path`
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        let write = match extension.to_lowercase().as_str() {
+            "png" => Framebuffer::write_png,
+            "ppm" => |image: &Framebuffer, out| Ok(image.write_p6(out)?),
+            "pfm" => |image: &Framebuffer, out| Ok(image.write_pfm(out)?),
+            _ => {
+                return Err(format!(
+                    "{}: unknown image format, use .png, .ppm or .pfm",
+                    path.display()
+                )
+                .into())
+            }
+        };
+        let file = File::create(path)
+            .map_err(|err| format!("failed to create {}: {err}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        write(self, &mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Reads an ASCII (P3) or binary (P6) PPM image written with `gamma`
+    pub fn load_ppm(path: &Path, gamma: f64) -> Result<Framebuffer, Box<dyn Error>> {
+        let data =
+            fs::read(path).map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        let invalid = |what: &str| format!("{}: {what}", path.display());
+
+        // The magic number, width, height and maximum value separated by
+        // whitespace and comments, then a single whitespace before binary data
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while fields.len() < 4 {
+            match data.get(pos) {
+                Some(b'#') => {
+                    while data.get(pos).is_some_and(|&b| b != b'\n') {
+                        pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => pos += 1,
+                Some(_) => {
+                    let start = pos;
+                    while data.get(pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+                        pos += 1;
+                    }
+                    fields.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+                }
+                None => return Err(invalid("truncated header").into()),
+            }
+        }
+        let binary = match fields[0].as_str() {
+            "P3" => false,
+            "P6" => true,
+            magic => return Err(invalid(&format!("not a PPM image, starts with '{magic}'")).into()),
+        };
+        let number = |field: &str| {
+            field
+                .parse::<u32>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| invalid(&format!("invalid header value '{field}'")))
+        };
+        let (width, height, max) = (
+            number(&fields[1])?,
+            number(&fields[2])?,
+            number(&fields[3])?,
+        );
+        let count = width as usize * height as usize * 3;
+
+        let values: Vec<u32> = if !binary {
+            let text = String::from_utf8_lossy(&data[pos..]);
+            text.split_ascii_whitespace()
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| invalid(&format!("invalid value '{value}'")))
+                })
+                .collect::<Result<_, _>>()?
+        } else if max < 256 {
+            let data = data.get(pos + 1..).unwrap_or_default();
+            data.iter().map(|&b| b as u32).collect()
+        } else {
+            let data = data.get(pos + 1..).unwrap_or_default();
+            data.chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]) as u32)
+                .collect()
+        };
+        if values.len() != count {
+            return Err(invalid(&format!(
+                "expected {count} color values, found {}",
+                values.len()
+            ))
+            .into());
+        }
+
+        let mut image = Framebuffer::new(width, height, gamma);
+        for (pixel, rgb) in image.pixels.iter_mut().zip(values.chunks_exact(3)) {
+            let channel = |value: u32| (value.min(max) as f64 / max as f64).powf(gamma);
+            *pixel = Vec3::new(channel(rgb[0]), channel(rgb[1]), channel(rgb[2]));
+        }
+        Ok(image)
+    }
+}
@@ -1,81 +1,124 @@
-use rand::Rng;
-use std::{error::Error, fs::File, io::Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use std::{env, error::Error, process, thread};
 
 mod vec3;
 use vec3::Vec3;
 
+mod framebuffer;
+use framebuffer::Framebuffer;
+
 mod ray;
-use ray::Ray;
 
 mod hitable;
-use hitable::{Hitable, HitableList};
 
 mod sphere;
-use sphere::Sphere;
 
 mod camera;
-use camera::Camera;
-
-fn random_in_unit_sphere() -> Vec3 {
-    let unit = Vec3::new(1.0, 1.0, 1.0);
-    let mut rng = rand::rng();
-    loop {
-        let p =
-            2.0 * Vec3::new(
-                rng.random::<f64>(),
-                rng.random::<f64>(),
-                rng.random::<f64>(),
-            ) - unit;
-        if p.squared_length() < 1.0 {
-            break p;
+
+mod material;
+
+mod scene;
+use scene::Scene;
+
+mod render;
+use render::render;
+
+const USAGE: &str = "usage: raytracing-in-a-week render <scene.toml> [-o <out.png>] [-j <threads>]
+       raytracing-in-a-week bench <scene.toml> [-j <threads>]
+       raytracing-in-a-week convert <image.ppm> [-o <out.png>] [-g <gamma>]
+
+render writes the scene to images/<scene name>.png unless -o is given.
+bench renders it on 1, 2, 4... up to the given threads and prints the times.
+convert reads an ASCII or binary PPM image, written with gamma 2 unless -g is
+given, and writes it next to the input as PNG unless -o is given.
+Images are written as PNG, binary PPM or linear PFM depending on the extension.
+Threads default to the number of cores. Example scenes are in scenes/.";
+
+fn render_to_file(scene_path: &Path, output: &Path, threads: usize) -> Result<(), Box<dyn Error>> {
+    let scene = Scene::load(scene_path)?;
+    render(&scene, threads, true).save(output)
+}
+
+/// Renders the scene with twice the threads each time up to `max_threads`,
+/// checking every image matches the single threaded one
+fn bench(scene_path: &Path, max_threads: usize) -> Result<(), Box<dyn Error>> {
+    let scene = Scene::load(scene_path)?;
+    let mut counts: Vec<usize> = (0..)
+        .map(|n| 1 << n)
+        .take_while(|&threads| threads < max_threads)
+        .collect();
+    counts.push(max_threads);
+
+    println!("threads  seconds  speedup");
+    let mut baseline: Option<(f64, Framebuffer)> = None;
+    for threads in counts {
+        let start = Instant::now();
+        let image = render(&scene, threads, false);
+        let seconds = start.elapsed().as_secs_f64();
+        let (base_seconds, base_image) = baseline.get_or_insert((seconds, image.clone()));
+        if image != *base_image {
+            return Err(format!("the image rendered on {threads} threads differs").into());
         }
+        println!(
+            "{threads:>7}  {seconds:>7.3}  {:>6.2}x",
+            *base_seconds / seconds
+        );
     }
+    Ok(())
 }
 
-fn color(ray: &Ray, world: &HitableList) -> Vec3 {
-    if let Some(hit) = world.hit(ray, 0.001, f64::MAX) {
-        let target = hit.p - hit.normal + random_in_unit_sphere();
-        0.5 * color(&Ray::new(hit.p, target - hit.p), world)
-    } else {
-        let unit_dir = ray.direction().unit_vec();
-        let t = 0.5 * (unit_dir.y() + 1.0);
-        (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
-    }
+fn convert(input: &Path, output: &Path, gamma: f64) -> Result<(), Box<dyn Error>> {
+    Framebuffer::load_ppm(input, gamma)?.save(output)
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let nx = 200;
-    let ny = 100;
-    let ns = 100;
-    let cam = Camera::new();
-    let mut rng = rand::rng();
-
-    let mut image = String::new();
-    image.push_str(&format!("P3\n{nx} {ny}\n255\n"));
-    let world = Box::new(HitableList::new(vec![
-        Box::new(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5)),
-        Box::new(Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0)),
-    ]));
-
-    for j in (0..=ny - 1).rev() {
-        for i in 0..nx {
-            let mut col = Vec3::new(0.0, 0.0, 0.0);
-            for _ in 0..ns {
-                let u = (i as f64 + rng.random::<f64>()) / nx as f64;
-                let v = (j as f64 + rng.random::<f64>()) / ny as f64;
-                let r = cam.get_ray(u, v);
-                let _ = r.point_at_parameter(2.0);
-                col += color(&r, &world);
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let usage = || -> ! {
+        eprintln!("{USAGE}");
+        process::exit(2);
+    };
+    let (command, input) = match args.as_slice() {
+        [command, input, ..] if ["render", "bench", "convert"].contains(&command.as_str()) => {
+            (command.as_str(), Path::new(input))
+        }
+        _ => usage(),
+    };
+    let mut output = None;
+    let mut gamma = 2.0;
+    let mut threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut flags = args[2..].iter();
+    while let Some(flag) = flags.next() {
+        match (flag.as_str(), flags.next()) {
+            ("-o" | "--output", Some(path)) if command != "bench" => {
+                output = Some(PathBuf::from(path))
             }
-            col /= ns as f64;
-            col = col.sqrt();
-            let ir = (255.99 * col[0]) as u8;
-            let ig = (255.99 * col[1]) as u8;
-            let ib = (255.99 * col[2]) as u8;
-            image.push_str(&format!("{ir} {ig} {ib}\n"));
+            ("-j" | "--threads", Some(n)) if command != "convert" => match n.parse() {
+                Ok(n) if n > 0 => threads = n,
+                _ => usage(),
+            },
+            ("-g" | "--gamma", Some(g)) if command == "convert" => match g.parse() {
+                Ok(g) if g > 0.0 => gamma = g,
+                _ => usage(),
+            },
+            _ => usage(),
         }
     }
-    let mut image_file = File::create("images/chapter_7.2.ppm")?;
-    let _ = image_file.write(image.as_bytes());
-    Ok(())
+    let name = input.file_stem().unwrap_or_default().to_string_lossy();
+
+    let result = match command {
+        "bench" => bench(input, threads),
+        "convert" => {
+            let output = output.unwrap_or_else(|| input.with_file_name(format!("{name}.png")));
+            convert(input, &output, gamma)
+        }
+        _ => {
+            let output = output.unwrap_or_else(|| Path::new("images").join(format!("{name}.png")));
+            render_to_file(input, &output, threads)
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("error: {err}");
+        process::exit(1);
+    }
 }
@@ -1,3 +1,182 @@
-// pub struct Material {
-//     fn 
-// }
+use rand::{Rng, RngCore};
+
+use crate::hitable::HitRecord;
+use crate::{ray::Ray, Vec3};
+
+pub fn random_in_unit_sphere(rng: &mut dyn RngCore) -> Vec3 {
+    let unit = Vec3::new(1.0, 1.0, 1.0);
+    loop {
+        let p =
+            2.0 * Vec3::new(
+                rng.random::<f64>(),
+                rng.random::<f64>(),
+                rng.random::<f64>(),
+            ) - unit;
+        if p.squared_length() < 1.0 {
+            break p;
+        }
+    }
+}
+
+fn reflect(v: Vec3, n: Vec3) -> Vec3 {
+    v - 2.0 * v.dot(&n) * n
+}
+
+fn refract(v: Vec3, n: Vec3, ni_over_nt: f64) -> Option<Vec3> {
+    let uv = v.unit_vec();
+    let dt = uv.dot(&n);
+    let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
+    if discriminant > 0.0 {
+        Some(ni_over_nt * (uv - n * dt) - n * discriminant.sqrt())
+    } else {
+        None
+    }
+}
+
+/// Schlick's approximation of the reflectivity of glass at an angle
+fn schlick(cosine: f64, ref_idx: f64) -> f64 {
+    let r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
+    let r0 = r0 * r0;
+    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+}
+
+/// Shared by the render threads, so random numbers come from the caller's `rng`
+pub trait Material: Sync {
+    /// The attenuation and the scattered ray, or `None` if the ray is absorbed
+    fn scatter(&self, ray_in: &Ray, hit: &HitRecord, rng: &mut dyn RngCore) -> Option<(Vec3, Ray)>;
+
+    /// Color the surface gives off regardless of the light falling on it
+    fn emitted(&self, _hit: &HitRecord) -> Vec3 {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+}
+
+/// Diffuse surface that scatters in random directions
+pub struct Lambertian {
+    albedo: Vec3,
+}
+
+impl Lambertian {
+    pub fn new(albedo: Vec3) -> Lambertian {
+        Lambertian { albedo }
+    }
+}
+
+impl Material for Lambertian {
+    fn scatter(
+        &self,
+        _ray_in: &Ray,
+        hit: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<(Vec3, Ray)> {
+        let target = hit.p + hit.normal + random_in_unit_sphere(rng);
+        Some((self.albedo, Ray::new(hit.p, target - hit.p)))
+    }
+}
+
+/// Mirror, blurred by `fuzz` between 0 (polished) and 1
+pub struct Metal {
+    albedo: Vec3,
+    fuzz: f64,
+}
+
+impl Metal {
+    pub fn new(albedo: Vec3, fuzz: f64) -> Metal {
+        Metal {
+            albedo,
+            fuzz: fuzz.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Material for Metal {
+    fn scatter(&self, ray_in: &Ray, hit: &HitRecord, rng: &mut dyn RngCore) -> Option<(Vec3, Ray)> {
+        let reflected = reflect(ray_in.direction().unit_vec(), hit.normal);
+        let scattered = Ray::new(hit.p, reflected + self.fuzz * random_in_unit_sphere(rng));
+        if scattered.direction().dot(&hit.normal) > 0.0 {
+            Some((self.albedo, scattered))
+        } else {
+            None
+        }
+    }
+}
+
+/// Clear material like glass or water that reflects or refracts
+pub struct Dielectric {
+    ref_idx: f64,
+}
+
+impl Dielectric {
+    pub fn new(ref_idx: f64) -> Dielectric {
+        Dielectric { ref_idx }
+    }
+}
+
+impl Material for Dielectric {
+    fn scatter(&self, ray_in: &Ray, hit: &HitRecord, rng: &mut dyn RngCore) -> Option<(Vec3, Ray)> {
+        let direction = ray_in.direction();
+        let attenuation = Vec3::new(1.0, 1.0, 1.0);
+        let (outward_normal, ni_over_nt, cosine) = if direction.dot(&hit.normal) > 0.0 {
+            (
+                -hit.normal,
+                self.ref_idx,
+                self.ref_idx * direction.dot(&hit.normal) / direction.length(),
+            )
+        } else {
+            (
+                hit.normal,
+                1.0 / self.ref_idx,
+                -direction.dot(&hit.normal) / direction.length(),
+            )
+        };
+        let scattered = match refract(direction, outward_normal, ni_over_nt) {
+            Some(refracted) if rng.random::<f64>() >= schlick(cosine, self.ref_idx) => refracted,
+            _ => reflect(direction, hit.normal),
+        };
+        Some((attenuation, Ray::new(hit.p, scattered)))
+    }
+}
+
+/// Solid color that ignores the light, like the red sphere of chapter 4
+pub struct Flat {
+    color: Vec3,
+}
+
+impl Flat {
+    pub fn new(color: Vec3) -> Flat {
+        Flat { color }
+    }
+}
+
+impl Material for Flat {
+    fn scatter(
+        &self,
+        _ray_in: &Ray,
+        _hit: &HitRecord,
+        _rng: &mut dyn RngCore,
+    ) -> Option<(Vec3, Ray)> {
+        None
+    }
+
+    fn emitted(&self, _hit: &HitRecord) -> Vec3 {
+        self.color
+    }
+}
+
+/// Shades the surface by its normal, like the spheres of chapters 5 and 6
+pub struct Normal;
+
+impl Material for Normal {
+    fn scatter(
+        &self,
+        _ray_in: &Ray,
+        _hit: &HitRecord,
+        _rng: &mut dyn RngCore,
+    ) -> Option<(Vec3, Ray)> {
+        None
+    }
+
+    fn emitted(&self, hit: &HitRecord) -> Vec3 {
+        0.5 * (hit.normal + Vec3::new(1.0, 1.0, 1.0))
+    }
+}
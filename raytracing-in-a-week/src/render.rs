@@ -0,0 +1,133 @@
+use rand::rngs::SmallRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::framebuffer::Framebuffer;
+use crate::hitable::Hitable;
+use crate::scene::Scene;
+use crate::{ray::Ray, Vec3};
+
+/// Width and height of the tiles the image is split into between threads
+const TILE_SIZE: u32 = 16;
+
+fn color(ray: &Ray, scene: &Scene, depth: u32, u: f64, v: f64, rng: &mut dyn RngCore) -> Vec3 {
+    if let Some(hit) = scene.world.hit(ray, 0.001, f64::MAX) {
+        let emitted = hit.material.emitted(&hit);
+        match hit.material.scatter(ray, &hit, rng) {
+            Some((attenuation, scattered)) if depth < scene.max_depth => {
+                emitted + attenuation * color(&scattered, scene, depth + 1, u, v, rng)
+            }
+            _ => emitted,
+        }
+    } else {
+        scene.background.color(ray, u, v)
+    }
+}
+
+/// Linear color of pixel `i` from the left and `j` from the bottom.
+/// Each pixel draws from its own generator seeded with the scene's seed and the
+/// pixel's position, so the image does not depend on which thread renders it.
+fn pixel(scene: &Scene, i: u32, j: u32) -> Vec3 {
+    let (nx, ny, ns) = (scene.width, scene.height, scene.samples);
+    let index = j as u64 * nx as u64 + i as u64;
+    let mut rng = SmallRng::seed_from_u64(scene.seed.rotate_left(32) ^ index);
+    let mut col = Vec3::new(0.0, 0.0, 0.0);
+    for _ in 0..ns {
+        let u = (i as f64 + rng.random::<f64>()) / nx as f64;
+        let v = (j as f64 + rng.random::<f64>()) / ny as f64;
+        let r = scene.camera.get_ray_with(u, v, &mut rng);
+        col += color(&r, scene, 0, u, v, &mut rng);
+    }
+    col / ns as f64
+}
+
+/// A rectangle of pixels, `x` and `y` counted from the top left corner
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+fn tiles(width: u32, height: u32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y in (0..height).step_by(TILE_SIZE as usize) {
+        for x in (0..width).step_by(TILE_SIZE as usize) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: TILE_SIZE.min(width - x),
+                height: TILE_SIZE.min(height - y),
+            });
+        }
+    }
+    tiles
+}
+
+/// Renders the scene on `threads` threads, each taking the next tile until none
+/// are left. With `progress` the share of finished tiles is shown on stderr if
+/// it is a terminal.
+pub fn render(scene: &Scene, threads: usize, progress: bool) -> Framebuffer {
+    let (width, height) = (scene.width, scene.height);
+    let tiles = tiles(width, height);
+    let next = AtomicUsize::new(0);
+    let progress = progress && io::stderr().is_terminal();
+    // The image and the number of tiles written to it
+    let image = Mutex::new((Framebuffer::new(width, height, scene.gamma), 0));
+
+    thread::scope(|s| {
+        for _ in 0..threads.max(1) {
+            s.spawn(|| {
+                while let Some(tile) = tiles.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let mut pixels = Vec::with_capacity((tile.width * tile.height) as usize);
+                    for y in tile.y..tile.y + tile.height {
+                        for x in tile.x..tile.x + tile.width {
+                            pixels.push(pixel(scene, x, height - 1 - y));
+                        }
+                    }
+
+                    let mut image = image.lock().unwrap();
+                    let (buffer, done) = &mut *image;
+                    for (row, y) in pixels.chunks(tile.width as usize).zip(tile.y..) {
+                        for (&col, x) in row.iter().zip(tile.x..) {
+                            buffer.set(x, y, col);
+                        }
+                    }
+                    *done += 1;
+                    if progress {
+                        eprint!("\rRendering {:3}%", *done * 100 / tiles.len());
+                    }
+                }
+            });
+        }
+    });
+    if progress {
+        eprintln!();
+    }
+    image.into_inner().unwrap().0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn same_seed_same_image_on_any_number_of_threads() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/chapter_11.toml");
+        let mut scene = Scene::load(&path).unwrap();
+        // Not a multiple of the tile size, so the tiles at the edges are partial
+        (scene.width, scene.height, scene.samples) = (40, 20, 4);
+        scene.seed = 7;
+
+        let image = render(&scene, 1, false);
+        assert!(image == render(&scene, 4, false));
+        assert!(image == render(&scene, 7, false));
+
+        scene.seed = 8;
+        assert!(image != render(&scene, 4, false));
+    }
+}
@@ -0,0 +1,201 @@
+use serde::Deserialize;
+use std::{collections::BTreeMap, error::Error, fs, path::Path};
+
+use crate::camera::Camera;
+use crate::hitable::{Hitable, HitableList};
+use crate::material::{Dielectric, Flat, Lambertian, Material, Metal, Normal};
+use crate::sphere::Sphere;
+use crate::{ray::Ray, Vec3};
+
+/// Color of the rays that hit nothing
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Background {
+    /// White at the horizon fading to blue overhead
+    #[default]
+    Sky,
+    /// Red across and green up the image, the first image of the book
+    Gradient,
+}
+
+impl Background {
+    /// `u` and `v` are where the ray's pixel is on the image, from 0 to 1
+    pub fn color(&self, ray: &Ray, u: f64, v: f64) -> Vec3 {
+        match self {
+            Background::Sky => {
+                let unit_dir = ray.direction().unit_vec();
+                let t = 0.5 * (unit_dir.y() + 1.0);
+                (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
+            }
+            Background::Gradient => Vec3::new(u, v, 0.2),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    look_from: Vec3,
+    look_at: Vec3,
+    #[serde(default = "default_up")]
+    up: Vec3,
+    /// Vertical field of view in degrees
+    vfov: f64,
+    #[serde(default)]
+    aperture: f64,
+    /// Defaults to the distance from `look_from` to `look_at`
+    focus_dist: Option<f64>,
+}
+
+fn default_up() -> Vec3 {
+    Vec3::new(0.0, 1.0, 0.0)
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum MaterialDesc {
+    Lambertian {
+        albedo: Vec3,
+    },
+    Metal {
+        albedo: Vec3,
+        #[serde(default)]
+        fuzz: f64,
+    },
+    Dielectric {
+        ref_idx: f64,
+    },
+    Flat {
+        color: Vec3,
+    },
+    Normal,
+}
+
+impl MaterialDesc {
+    fn build(&self) -> Box<dyn Material> {
+        match *self {
+            MaterialDesc::Lambertian { albedo } => Box::new(Lambertian::new(albedo)),
+            MaterialDesc::Metal { albedo, fuzz } => Box::new(Metal::new(albedo, fuzz)),
+            MaterialDesc::Dielectric { ref_idx } => Box::new(Dielectric::new(ref_idx)),
+            MaterialDesc::Flat { color } => Box::new(Flat::new(color)),
+            MaterialDesc::Normal => Box::new(Normal),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum ObjectDesc {
+    /// A negative radius turns the sphere inside out, for hollow glass
+    Sphere {
+        center: Vec3,
+        radius: f64,
+        material: String,
+    },
+}
+
+fn default_samples() -> u32 {
+    100
+}
+
+fn default_max_depth() -> u32 {
+    50
+}
+
+fn default_gamma() -> f64 {
+    2.0
+}
+
+/// A scene file, see `scenes/` for examples
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    width: u32,
+    height: u32,
+    /// Rays averaged per pixel
+    #[serde(default = "default_samples")]
+    samples: u32,
+    /// Bounces after which a ray counts as absorbed
+    #[serde(default = "default_max_depth")]
+    max_depth: u32,
+    /// 1 writes the linear color
+    #[serde(default = "default_gamma")]
+    gamma: f64,
+    #[serde(default)]
+    background: Background,
+    /// Seeds the random numbers, the same seed renders the same image
+    #[serde(default)]
+    seed: u64,
+    camera: CameraDesc,
+    #[serde(default)]
+    materials: BTreeMap<String, MaterialDesc>,
+    #[serde(default)]
+    objects: Vec<ObjectDesc>,
+}
+
+pub struct Scene {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub max_depth: u32,
+    pub gamma: f64,
+    pub background: Background,
+    pub seed: u64,
+    pub camera: Camera,
+    pub world: HitableList,
+}
+
+impl Scene {
+    pub fn load(path: &Path) -> Result<Scene, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        let desc: SceneDesc =
+            toml::from_str(&text).map_err(|err| format!("{}: {err}", path.display()))?;
+        if desc.width == 0 || desc.height == 0 || desc.samples == 0 {
+            return Err(format!(
+                "{}: width, height and samples must be positive",
+                path.display()
+            )
+            .into());
+        }
+
+        let mut list: Vec<Box<dyn Hitable>> = Vec::new();
+        for object in &desc.objects {
+            match object {
+                ObjectDesc::Sphere {
+                    center,
+                    radius,
+                    material,
+                } => {
+                    let material = desc.materials.get(material).ok_or_else(|| {
+                        format!("{}: unknown material '{material}'", path.display())
+                    })?;
+                    list.push(Box::new(Sphere::new(*center, *radius, material.build())));
+                }
+            }
+        }
+
+        let cam = &desc.camera;
+        let camera = Camera::new(
+            cam.look_from,
+            cam.look_at,
+            cam.up,
+            cam.vfov,
+            desc.width as f64 / desc.height as f64,
+            cam.aperture,
+            cam.focus_dist
+                .unwrap_or_else(|| (cam.look_from - cam.look_at).length()),
+        );
+        Ok(Scene {
+            width: desc.width,
+            height: desc.height,
+            samples: desc.samples,
+            max_depth: desc.max_depth,
+            gamma: desc.gamma,
+            background: desc.background,
+            seed: desc.seed,
+            camera,
+            world: HitableList::new(list),
+        })
+    }
+}
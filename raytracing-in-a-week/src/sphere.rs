@@ -1,19 +1,25 @@
 use crate::hitable::{HitRecord, Hitable};
+use crate::material::Material;
 use crate::{ray::Ray, Vec3};
 
 pub struct Sphere {
     center: Vec3,
     radius: f64,
+    material: Box<dyn Material>,
 }
 
 impl Sphere {
-    pub fn new(center: Vec3, radius: f64) -> Sphere {
-        Sphere { center, radius }
+    pub fn new(center: Vec3, radius: f64, material: Box<dyn Material>) -> Sphere {
+        Sphere {
+            center,
+            radius,
+            material,
+        }
     }
 }
 
 impl Hitable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let oc = ray.origin() - self.center;
         let a = ray.direction().dot(&ray.direction());
         let b = oc.dot(&ray.direction()) * 2.0;
@@ -24,15 +30,45 @@ impl Hitable for Sphere {
             if t < t_max && t > t_min {
                 let p = ray.point_at_parameter(t);
                 let normal = (p - self.center) / self.radius;
-                return Some(HitRecord { t, p, normal, })
-            } 
-            let t = (-b - discriminant.sqrt()) / (2.0 * a);
+                return Some(HitRecord {
+                    t,
+                    p,
+                    normal,
+                    material: &*self.material,
+                });
+            }
+            // The far root, for rays that start inside the sphere
+            let t = (-b + discriminant.sqrt()) / (2.0 * a);
             if t < t_max && t > t_min {
                 let p = ray.point_at_parameter(t);
                 let normal = (p - self.center) / self.radius;
-                return Some(HitRecord { t, p, normal })
+                return Some(HitRecord {
+                    t,
+                    p,
+                    normal,
+                    material: &*self.material,
+                });
             }
         }
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Dielectric;
+
+    #[test]
+    fn rays_from_inside_hit_the_far_side() {
+        let sphere = Sphere::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            1.0,
+            Box::new(Dielectric::new(1.5)),
+        );
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let hit = sphere.hit(&ray, 0.001, f64::MAX).unwrap();
+        assert_eq!(hit.t, 1.0);
+        assert_eq!(hit.p, Vec3::new(1.0, 0.0, 0.0));
+    }
+}
@@ -1,4 +1,5 @@
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(from = "[f64; 3]")]
 pub struct Vec3 {
     x: f64,
     y: f64,
@@ -61,6 +62,16 @@ impl Vec3 {
     pub fn sqrt(&self) -> Vec3 {
         Vec3::new(self.x.sqrt(), self.y.sqrt(), self.z.sqrt())
     }
+
+    pub fn powf(&self, n: f64) -> Vec3 {
+        Vec3::new(self.x.powf(n), self.y.powf(n), self.z.powf(n))
+    }
+}
+
+impl From<[f64; 3]> for Vec3 {
+    fn from([x, y, z]: [f64; 3]) -> Self {
+        Vec3 { x, y, z }
+    }
 }
 
 impl std::ops::Neg for Vec3 {